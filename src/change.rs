@@ -0,0 +1,243 @@
+use crate::error::BdError;
+use crate::types::{FileMetadata, InodeId};
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// What a file looked like when it was hashed. Comparing stamps right before
+/// a file is replaced catches writes that happened in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime_ns: i128,
+    pub ctime_ns: i128,
+    pub nlink: u64,
+}
+
+impl FileStamp {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.len(),
+            mtime_ns: meta.mtime() as i128 * 1_000_000_000 + meta.mtime_nsec() as i128,
+            ctime_ns: meta.ctime() as i128 * 1_000_000_000 + meta.ctime_nsec() as i128,
+            nlink: meta.nlink(),
+        }
+    }
+
+    /// Stats `path`, following symlinks. On Linux this is a `statx` asking
+    /// only for the fields of a stamp, which filesystems can answer without
+    /// filling in the rest.
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        match statx(path) {
+            Err(err) if err.raw_os_error() == Some(nix::libc::ENOSYS) => {}
+            result => return result,
+        }
+        std::fs::metadata(path).map(|meta| Self::from_metadata(&meta))
+    }
+
+    pub fn inode(&self) -> InodeId {
+        InodeId {
+            dev: self.dev,
+            ino: self.ino,
+        }
+    }
+
+    /// Whether `record` can describe this file. An inode freed by a delete
+    /// may be handed to an unrelated file, which then has the same device and
+    /// inode but not the recorded size and modification time.
+    pub fn matches(&self, record: &FileMetadata) -> bool {
+        record.size == self.size && record.modified == (self.mtime_ns / 1_000_000_000) as u64
+    }
+
+    /// Names the first field that differs from `earlier`, if any.
+    pub fn changed_since(&self, earlier: &FileStamp) -> Option<&'static str> {
+        if (self.dev, self.ino) != (earlier.dev, earlier.ino) {
+            Some("replaced by another file")
+        } else if self.size != earlier.size {
+            Some("size changed")
+        } else if self.mtime_ns != earlier.mtime_ns {
+            Some("modification time changed")
+        } else if self.ctime_ns != earlier.ctime_ns && earlier.nlink == 1 {
+            // Replacing one name of a multiply linked file bumps the ctime of
+            // the others, so ctime is only trusted for single-link files.
+            Some("inode change time changed")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn statx(path: &Path) -> std::io::Result<FileStamp> {
+    use nix::libc;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mask = libc::STATX_INO
+        | libc::STATX_SIZE
+        | libc::STATX_MTIME
+        | libc::STATX_CTIME
+        | libc::STATX_NLINK;
+    let mut buf = std::mem::MaybeUninit::<libc::statx>::zeroed();
+    // SAFETY: `path` is NUL-terminated and `buf` is a writable statx buffer.
+    let res = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::AT_STATX_SYNC_AS_STAT,
+            mask,
+            buf.as_mut_ptr(),
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: statx succeeded and filled in the buffer.
+    let stx = unsafe { buf.assume_init() };
+    if stx.stx_mask & mask != mask {
+        return Err(std::io::Error::from_raw_os_error(libc::ENOSYS));
+    }
+    let nanos = |ts: libc::statx_timestamp| ts.tv_sec as i128 * 1_000_000_000 + ts.tv_nsec as i128;
+    Ok(FileStamp {
+        dev: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+        ino: stx.stx_ino,
+        size: stx.stx_size,
+        mtime_ns: nanos(stx.stx_mtime),
+        ctime_ns: nanos(stx.stx_ctime),
+        nlink: u64::from(stx.stx_nlink),
+    })
+}
+
+/// Fails with [`BdError::FileChanged`] unless `path` still matches `expected`.
+pub fn check_unchanged(path: &Path, expected: &FileStamp) -> Result<(), BdError> {
+    let stamp = FileStamp::of(path).map_err(|err| BdError::file(path, err.into()))?;
+    match stamp.changed_since(expected) {
+        Some(reason) => Err(BdError::FileChanged {
+            path: path.to_path_buf(),
+            reason: reason.into(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// A read lease on a file, held while it is being replaced.
+///
+/// The kernel refuses the lease while anyone has the file open for writing,
+/// and flags it as broken when someone opens it for writing later on. Leases
+/// need file ownership or `CAP_LEASE` and are not supported everywhere; in
+/// that case the guard is empty and only the stamp checks protect the file.
+///
+/// A broken lease is signalled with `SIGIO`, so [`ignore_lease_breaks`] must
+/// have been called before the first lease is taken.
+pub struct Lease {
+    #[cfg(target_os = "linux")]
+    file: Option<std::fs::File>,
+}
+
+/// Ignores `SIGIO` for the rest of the process. Its default action kills the
+/// process; broken leases are polled with `F_GETLEASE` instead.
+#[cfg(target_os = "linux")]
+pub fn ignore_lease_breaks() {
+    use nix::libc;
+
+    // SAFETY: installs SIG_IGN, which runs no code in signal context.
+    unsafe { libc::signal(libc::SIGIO, libc::SIG_IGN) };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn ignore_lease_breaks() {}
+
+#[cfg(target_os = "linux")]
+impl Lease {
+    pub fn acquire(path: &Path) -> Result<Self, BdError> {
+        use nix::errno::Errno;
+        use nix::libc;
+        use std::os::unix::io::AsRawFd;
+
+        let file = std::fs::File::open(path).map_err(|err| BdError::file(path, err.into()))?;
+        // SAFETY: plain fcntl on a descriptor owned by `file`.
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLEASE, libc::F_RDLCK) };
+        match Errno::result(res) {
+            Ok(_) => Ok(Self { file: Some(file) }),
+            Err(Errno::EAGAIN) => Err(BdError::FileChanged {
+                path: path.to_path_buf(),
+                reason: "open for writing by another process".into(),
+            }),
+            Err(_) => Ok(Self { file: None }),
+        }
+    }
+
+    /// Fails with [`BdError::FileChanged`] if someone opened the file for
+    /// writing while the lease was held.
+    pub fn check_intact(&self, path: &Path) -> Result<(), BdError> {
+        use nix::libc;
+        use std::os::unix::io::AsRawFd;
+
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        // SAFETY: plain fcntl on a descriptor owned by `file`.
+        let lease = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLEASE) };
+        if lease == libc::F_RDLCK {
+            Ok(())
+        } else {
+            Err(BdError::FileChanged {
+                path: path.to_path_buf(),
+                reason: "opened for writing during the swap".into(),
+            })
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Lease {
+    pub fn acquire(_path: &Path) -> Result<Self, BdError> {
+        Ok(Self {})
+    }
+
+    pub fn check_intact(&self, _path: &Path) -> Result<(), BdError> {
+        Ok(())
+    }
+}
+
+/// Takes a lease on `path` and then confirms it still matches the stamp taken
+/// when it was hashed. Once this succeeds, any later writer breaks the lease.
+pub fn guard(path: &Path, expected: Option<&FileStamp>) -> Result<Lease, BdError> {
+    let lease = Lease::acquire(path)?;
+    if let Some(expected) = expected {
+        check_unchanged(path, expected)?;
+    }
+    Ok(lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_written_after_their_stamp_are_reported_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"stamped").unwrap();
+        let stamp = FileStamp::of(&path).unwrap();
+        check_unchanged(&path, &stamp).unwrap();
+
+        // Same size, so only the modification time gives the write away.
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(1);
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(later).unwrap();
+
+        match check_unchanged(&path, &stamp) {
+            Err(BdError::FileChanged { reason, .. }) => {
+                assert!(reason.contains("modification time changed"), "{reason}");
+            }
+            other => panic!("expected a changed file, got {other:?}"),
+        }
+    }
+}
@@ -1,27 +1,66 @@
+use crate::change::{self, FileStamp};
+use crate::hasher;
+use crate::staging::StagedFile;
+use crate::trash::Trash;
+use crate::types::Hash;
 use anyhow::{Context, Result};
 use filetime::FileTime;
-use std::ffi::OsString;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{BufReader, Read};
-use std::path::{Path, PathBuf};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LinkType {
     Reflink,
     HardLink,
+    Symlink,
+    DedupeRange,
+    Copy,
+    Deleted,
 }
 
-struct TempCleanup {
+/// How duplicates are resolved during a dedupe run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DedupeMode {
+    /// Share data with the vault through reflinks (or hard links when allowed).
+    Link,
+    /// Replace duplicates with symlinks relative to the duplicate's directory.
+    Symlink,
+    /// Replace duplicates with absolute symlinks to the master.
+    AbsoluteSymlink,
+    /// Delete duplicates, or move them to the trash directory when one is given.
+    Delete,
+}
+
+/// What a dedupe does with a file that has hard links outside the scanned
+/// root, which keep its data alive whatever happens to the names inside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExternalLinks {
+    /// Leave the file and every name of it alone.
+    Skip,
+    /// Link the file and relink its other names under the root to it; the
+    /// names outside keep the old data.
+    RelinkAll,
+    /// Link every name under the root on its own, as if it were a copy.
+    Break,
+}
+
+pub struct TempCleanup {
     path: PathBuf,
     armed: bool,
 }
 
 impl TempCleanup {
-    fn new(path: PathBuf) -> Self {
+    pub fn new(path: PathBuf) -> Self {
         Self { path, armed: true }
     }
 
-    fn disarm(&mut self) {
+    pub fn disarm(&mut self) {
         self.armed = false;
     }
 }
@@ -31,94 +70,415 @@ impl Drop for TempCleanup {
         if !self.armed {
             return;
         }
-        if self.path.exists() {
+        if std::fs::symlink_metadata(&self.path).is_ok() {
             let _ = std::fs::remove_file(&self.path);
         }
     }
 }
 
-pub fn replace_with_link(
+pub fn delete_duplicate(
     master: &Path,
     target: &Path,
-    allow_unsafe_hardlinks: bool,
+    hash: &Hash,
+    trash: Option<&mut Trash>,
 ) -> Result<Option<LinkType>> {
     if master == target {
         return Ok(None);
     }
 
-    let mut temp = target.to_path_buf();
-    temp.set_extension("imprint_tmp");
-    if temp.exists() {
-        std::fs::remove_file(&temp).with_context(|| "remove existing temp file")?;
+    match trash {
+        Some(trash) => trash.stash(target, hash)?,
+        None => std::fs::remove_file(target).with_context(|| "delete duplicate")?,
     }
 
-    let mut cleanup = TempCleanup::new(temp.clone());
+    Ok(Some(LinkType::Deleted))
+}
 
-    match reflink::reflink(master, &temp) {
-        Ok(_) => {
-            let target_meta = std::fs::metadata(target).with_context(|| "read target metadata")?;
-            let target_permissions = target_meta.permissions();
-            let target_mtime = FileTime::from_last_modification_time(&target_meta);
-
-            let mut target_xattrs: Vec<(OsString, Vec<u8>)> = Vec::new();
-            if let Ok(attrs) = xattr::list(target) {
-                for attr_name in attrs {
-                    if let Ok(Some(attr_value)) = xattr::get(target, &attr_name) {
-                        target_xattrs.push((attr_name, attr_value));
-                    }
+/// Builds the path that leads from `from_dir` to `to`. Both must be absolute.
+pub fn relative_path(from_dir: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from_dir.components().collect();
+    let to_components: Vec<Component> = to.components().collect();
+
+    let common = from
+        .iter()
+        .zip(to_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to_components[common..] {
+        relative.push(component.as_os_str());
+    }
+    relative
+}
+
+/// Ownership, permissions, timestamps, extended attributes (POSIX ACLs
+/// included) and inode flags of a file, captured before it is replaced so
+/// they can be put back on the new inode. It is also stored in the state
+/// database so a restore can undo hard links, which share one inode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedMetadata {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    /// Seconds and nanoseconds since the epoch.
+    atime: (i64, u32),
+    mtime: (i64, u32),
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    flags: Option<i32>,
+}
+
+impl SavedMetadata {
+    pub fn capture(path: &Path) -> Result<Self> {
+        let meta = std::fs::metadata(path).with_context(|| "read target metadata")?;
+
+        let mut xattrs: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        if let Ok(attrs) = xattr::list(path) {
+            for attr_name in attrs {
+                if let Ok(Some(attr_value)) = xattr::get(path, &attr_name) {
+                    xattrs.push((attr_name.into_vec(), attr_value));
                 }
             }
+        }
 
-            std::fs::rename(&temp, target).with_context(|| "replace target with reflink")?;
-            cleanup.disarm();
+        Ok(Self {
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mode: meta.mode(),
+            atime: (meta.atime(), meta.atime_nsec() as u32),
+            mtime: (meta.mtime(), meta.mtime_nsec() as u32),
+            xattrs,
+            flags: read_inode_flags(path),
+        })
+    }
 
-            apply_metadata(target, &target_permissions, target_mtime, &target_xattrs)?;
+    /// Like `capture`, but a missing file (a master moved into the vault)
+    /// yields `None` instead of an error.
+    pub fn capture_if_exists(path: &Path) -> Result<Option<Self>> {
+        match std::fs::symlink_metadata(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            _ => Self::capture(path).map(Some),
+        }
+    }
 
-            Ok(Some(LinkType::Reflink))
+    /// Puts everything back on `path` and checks that it stuck. Extended
+    /// attributes (ACLs included) and inode flags are best effort: the ones
+    /// the filesystem or our privileges refuse are returned, described, as
+    /// losses. Only a mode, owner or times that did not stick is an error.
+    pub fn apply(&self, path: &Path) -> Result<Vec<String>> {
+        let mut lost = Vec::new();
+        let meta = std::fs::metadata(path).with_context(|| "read new file metadata")?;
+        // Changing the owner clears setuid/setgid bits, so it goes first. A
+        // refused chown is only fatal if the check below sees the difference.
+        let mut chown_error = None;
+        if (meta.uid(), meta.gid()) != (self.uid, self.gid) {
+            chown_error = std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid)).err();
         }
-        Err(_) => {
-            if temp.exists() {
-                let _ = std::fs::remove_file(&temp);
+
+        std::fs::set_permissions(path, self.permissions())
+            .with_context(|| "restore file permissions")?;
+
+        // ACLs are stored as `system.posix_acl_*` attributes and come after
+        // the mode, which would otherwise overwrite the ACL mask.
+        for (attr_name, attr_value) in &self.xattrs {
+            let attr_name = OsStr::from_bytes(attr_name);
+            let restored =
+                xattr::set(path, attr_name, attr_value).and_then(|()| {
+                    match xattr::get(path, attr_name)? {
+                        Some(value) if value == *attr_value => Ok(()),
+                        _ => Err(std::io::Error::other("value differs after setting")),
+                    }
+                });
+            if let Err(err) = restored {
+                lost.push(format!("extended attribute {attr_name:?} ({err})"));
             }
+        }
 
-            if allow_unsafe_hardlinks {
-                std::fs::hard_link(master, &temp).with_context(|| "create hard link")?;
-                std::fs::rename(&temp, target).with_context(|| "replace target with hard link")?;
-                cleanup.disarm();
+        filetime::set_file_times(path, self.atime(), self.mtime())
+            .with_context(|| "restore file times")?;
+
+        // Flags such as immutable or append-only would block the steps above.
+        if let Some(flags) = self.flags
+            && read_inode_flags(path) != Some(flags)
+        {
+            let restored = write_inode_flags(path, flags).and_then(|()| {
+                if read_inode_flags(path) == Some(flags) {
+                    Ok(())
+                } else {
+                    anyhow::bail!("flags differ after setting")
+                }
+            });
+            if let Err(err) = restored {
+                lost.push(format!("inode flags ({err:#})"));
+            }
+        }
 
-                Ok(Some(LinkType::HardLink))
-            } else {
-                anyhow::bail!(
-                    "reflink not supported on this filesystem and --allow-unsafe-hardlinks not specified"
-                )
+        match (self.check(path), chown_error) {
+            (Err(err), Some(chown_error)) => {
+                Err(err.context(format!("restore file ownership: {chown_error}")))
             }
+            (result, _) => result.map(|()| lost),
         }
     }
-}
 
-fn apply_metadata(
-    path: &Path,
-    permissions: &std::fs::Permissions,
-    mtime: FileTime,
-    xattrs: &[(OsString, Vec<u8>)],
-) -> Result<()> {
-    std::fs::set_permissions(path, permissions.clone())
-        .with_context(|| "restore file permissions")?;
+    /// Fails naming the first of the mode, owner and times of `path` that
+    /// differs from the saved ones.
+    fn check(&self, path: &Path) -> Result<()> {
+        let meta = std::fs::metadata(path).with_context(|| "read new file metadata")?;
+        let mismatch = if (meta.uid(), meta.gid()) != (self.uid, self.gid) {
+            Some("ownership")
+        } else if meta.permissions() != self.permissions() {
+            Some("permissions")
+        } else if FileTime::from_last_modification_time(&meta) != self.mtime() {
+            Some("mtime")
+        } else if FileTime::from_last_access_time(&meta) != self.atime() {
+            Some("atime")
+        } else {
+            None
+        };
+        match mismatch {
+            Some(what) => anyhow::bail!("metadata not preserved: {what} differ"),
+            None => Ok(()),
+        }
+    }
 
-    filetime::set_file_mtime(path, mtime).with_context(|| "restore file mtime")?;
+    fn permissions(&self) -> std::fs::Permissions {
+        std::fs::Permissions::from_mode(self.mode)
+    }
 
-    for (attr_name, attr_value) in xattrs {
-        let _ = xattr::set(path, attr_name, attr_value);
+    fn atime(&self) -> FileTime {
+        FileTime::from_unix_time(self.atime.0, self.atime.1)
     }
 
+    fn mtime(&self) -> FileTime {
+        FileTime::from_unix_time(self.mtime.0, self.mtime.1)
+    }
+}
+
+/// Reads the `chattr` flags of `path`, or `None` where the filesystem has none.
+#[cfg(target_os = "linux")]
+fn read_inode_flags(path: &Path) -> Option<i32> {
+    use std::os::unix::io::AsRawFd;
+
+    // The request number is declared with `long`, but the kernel transfers
+    // an `int`.
+    nix::ioctl_read_bad!(
+        fs_ioc_getflags,
+        nix::request_code_read!(b'f', 1, std::mem::size_of::<nix::libc::c_long>()),
+        i32
+    );
+
+    let file = File::open(path).ok()?;
+    let mut flags = 0;
+    // SAFETY: FS_IOC_GETFLAGS writes a single int into `flags`.
+    unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) }.ok()?;
+    Some(flags)
+}
+
+#[cfg(target_os = "linux")]
+fn write_inode_flags(path: &Path, flags: i32) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    nix::ioctl_write_ptr_bad!(
+        fs_ioc_setflags,
+        nix::request_code_write!(b'f', 2, std::mem::size_of::<nix::libc::c_long>()),
+        i32
+    );
+
+    let file = File::open(path).with_context(|| "open file to set flags")?;
+    // SAFETY: FS_IOC_SETFLAGS reads a single int from `flags`.
+    unsafe { fs_ioc_setflags(file.as_raw_fd(), &flags) }?;
     Ok(())
 }
 
+#[cfg(not(target_os = "linux"))]
+fn read_inode_flags(_path: &Path) -> Option<i32> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_inode_flags(_path: &Path, _flags: i32) -> Result<()> {
+    Ok(())
+}
+
+/// Returns a fresh sibling path to stage a replacement for `target` under.
+/// The whole file name is kept and made unique to this process and call, so
+/// `f.txt` and `f.md` linked at the same time never share a temp name. The
+/// path is not created here; callers create it exclusively and only clean
+/// up what they created.
+pub fn temp_path(target: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".{}-{}.imprint_tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    target.with_file_name(name)
+}
+
+/// Makes `alias`, another name of the file `primary` was before it was
+/// linked, a hard link to `primary` again. `stamp` describes the file as it
+/// was hashed; an alias that no longer matches it is left alone.
+pub fn relink_alias(primary: &Path, alias: &Path, stamp: Option<&FileStamp>) -> Result<()> {
+    let primary_meta = std::fs::metadata(primary).with_context(|| "read linked file")?;
+    let alias_meta = std::fs::metadata(alias).with_context(|| "read alias")?;
+    if (primary_meta.dev(), primary_meta.ino()) == (alias_meta.dev(), alias_meta.ino()) {
+        return Ok(());
+    }
+    if let Some(stamp) = stamp {
+        change::check_unchanged(alias, stamp)?;
+    }
+    let temp = temp_path(alias);
+    std::fs::hard_link(primary, &temp).with_context(|| "link alias to the linked file")?;
+    let mut cleanup = TempCleanup::new(temp.clone());
+    std::fs::rename(&temp, alias).with_context(|| "replace alias")?;
+    cleanup.disarm();
+    Ok(())
+}
+
+/// How a [`Swap`] is undone.
+enum Undo {
+    /// The original was exchanged to the temp path and can be swapped back.
+    Exchange(PathBuf),
+    /// Nothing was at the target before; undoing removes the new file.
+    Remove,
+    /// The target was changed in place; undoing gives it a private copy.
+    Unshare,
+    /// The filesystem cannot exchange, so the original was linked to this
+    /// backup name before the replacement took its place.
+    Backup(PathBuf),
+}
+
+/// A replacement that has been put in place but not yet committed.
+///
+/// Until [`Swap::commit`] is called the original is kept under the temp name,
+/// so a failed verification can put it back with [`Swap::rollback`]. Dropping
+/// an uncommitted swap rolls it back.
+pub struct Swap {
+    target: PathBuf,
+    undo: Option<Undo>,
+    /// Metadata the replacement could not be given; see [`SavedMetadata::apply`].
+    pub lost: Vec<String>,
+}
+
+impl Swap {
+    /// Puts `temp` in place of `target` with `RENAME_EXCHANGE`, leaving the
+    /// original at `temp`. Uses a plain rename when `target` does not exist.
+    /// Where the filesystem cannot exchange, the original is first hard
+    /// linked to a backup name and the replacement renamed over it, so the
+    /// original can still be put back. Filesystems without either are
+    /// refused rather than overwritten.
+    pub fn exchange(temp: &Path, target: &Path) -> Result<Self> {
+        let undo = if std::fs::symlink_metadata(target).is_err() {
+            std::fs::rename(temp, target).with_context(|| "move replacement into place")?;
+            Undo::Remove
+        } else if exchange_paths(temp, target)? {
+            Undo::Exchange(temp.to_path_buf())
+        } else {
+            return Self::backed_up(temp, target);
+        };
+        Ok(Self {
+            target: target.to_path_buf(),
+            undo: Some(undo),
+            lost: Vec::new(),
+        })
+    }
+
+    /// Renames `temp` over `target` after hard linking the original to a
+    /// backup name, for filesystems that cannot exchange.
+    fn backed_up(temp: &Path, target: &Path) -> Result<Self> {
+        let backup = temp_path(target);
+        std::fs::hard_link(target, &backup).with_context(|| "back up original")?;
+        if let Err(err) = std::fs::rename(temp, target) {
+            let _ = std::fs::remove_file(&backup);
+            return Err(err).with_context(|| "replace target");
+        }
+        Ok(Self {
+            target: target.to_path_buf(),
+            undo: Some(Undo::Backup(backup)),
+            lost: Vec::new(),
+        })
+    }
+
+    /// A swap for a target that was modified in place rather than replaced.
+    pub fn in_place(target: &Path) -> Self {
+        Self {
+            target: target.to_path_buf(),
+            undo: Some(Undo::Unshare),
+            lost: Vec::new(),
+        }
+    }
+
+    /// Keeps the replacement and discards the original.
+    pub fn commit(mut self) -> Result<()> {
+        if let Some(Undo::Exchange(original) | Undo::Backup(original)) = self.undo.take() {
+            std::fs::remove_file(&original).with_context(|| "remove replaced original")?;
+        }
+        Ok(())
+    }
+
+    /// Puts the original back in place.
+    pub fn rollback(mut self) -> Result<()> {
+        match self.undo.take() {
+            Some(undo) => undo_swap(&self.target, undo),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Swap {
+    fn drop(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            let _ = undo_swap(&self.target, undo);
+        }
+    }
+}
+
+fn undo_swap(target: &Path, undo: Undo) -> Result<()> {
+    match undo {
+        Undo::Exchange(original) => {
+            if !exchange_paths(&original, target)? {
+                anyhow::bail!("swap back original: exchange no longer supported");
+            }
+            std::fs::remove_file(&original).with_context(|| "remove rolled back replacement")
+        }
+        Undo::Remove => std::fs::remove_file(target).with_context(|| "remove replacement"),
+        Undo::Unshare => restore_file(target, None).map(drop),
+        Undo::Backup(backup) => {
+            std::fs::rename(&backup, target).with_context(|| "move original back from backup")
+        }
+    }
+}
+
+/// Atomically swaps two paths. Returns `false` when the filesystem or kernel
+/// cannot do it.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn exchange_paths(a: &Path, b: &Path) -> Result<bool> {
+    use nix::errno::Errno;
+    use nix::fcntl::{RenameFlags, renameat2};
+
+    match renameat2(None, a, None, b, RenameFlags::RENAME_EXCHANGE) {
+        Ok(()) => Ok(true),
+        Err(Errno::EINVAL | Errno::ENOSYS | Errno::EOPNOTSUPP) => Ok(false),
+        Err(err) => Err(err).with_context(|| "exchange files"),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn exchange_paths(_a: &Path, _b: &Path) -> Result<bool> {
+    Ok(false)
+}
+
 pub fn compare_files(path1: &Path, path2: &Path) -> Result<bool> {
     const BUFFER_SIZE: usize = 128 * 1024;
 
-    let file1 = File::open(path1).with_context(|| "open file for compare (path1)")?;
-    let file2 = File::open(path2).with_context(|| "open file for compare (path2)")?;
+    let file1 = hasher::open_noatime(path1).with_context(|| "open file for compare (path1)")?;
+    let file2 = hasher::open_noatime(path2).with_context(|| "open file for compare (path2)")?;
 
     let mut reader1 = BufReader::with_capacity(BUFFER_SIZE, file1);
     let mut reader2 = BufReader::with_capacity(BUFFER_SIZE, file2);
@@ -142,38 +502,123 @@ pub fn compare_files(path1: &Path, path2: &Path) -> Result<bool> {
     }
 }
 
-pub fn restore_file(target: &Path) -> Result<()> {
-    let target_meta = std::fs::metadata(target).with_context(|| "read target metadata")?;
-    let target_permissions = target_meta.permissions();
-    let target_mtime = FileTime::from_last_modification_time(&target_meta);
+/// Replaces a symlink left by a symlink-mode dedupe with a private copy of the
+/// file it points to, carrying `original` metadata when it was recorded.
+/// Returns the metadata that could not be restored.
+pub fn restore_symlink(target: &Path, original: Option<&SavedMetadata>) -> Result<Vec<String>> {
+    let master_meta = std::fs::metadata(target).with_context(|| "read symlink target metadata")?;
+
+    let mut staged = StagedFile::create(target)?;
+    staged
+        .copy_from(target)
+        .with_context(|| "copy symlink target to temp file")?;
+    filetime::set_file_handle_times(
+        staged.file(),
+        None,
+        Some(FileTime::from_last_modification_time(&master_meta)),
+    )
+    .with_context(|| "restore file mtime")?;
+
+    let swap = staged.swap_into(target)?;
+    let lost = match original {
+        Some(original) => original.apply(target)?,
+        None => Vec::new(),
+    };
+    swap.commit()?;
+    Ok(lost)
+}
+
+/// Gives `target` a private copy of its data. Its metadata is taken from
+/// `original` when that was recorded before dedupe, and from the (possibly
+/// shared) inode otherwise. Returns the metadata that could not be restored.
+pub fn restore_file(target: &Path, original: Option<&SavedMetadata>) -> Result<Vec<String>> {
+    let saved = match original {
+        Some(original) => original.clone(),
+        None => SavedMetadata::capture(target)?,
+    };
+
+    let mut staged = StagedFile::create(target)?;
+    staged
+        .copy_from(target)
+        .with_context(|| "copy bytes to temp file")?;
+
+    let swap = staged.swap_into(target)?;
+    let lost = saved.apply(target)?;
+    swap.commit()?;
+    Ok(lost)
+}
 
-    let mut target_xattrs: Vec<(OsString, Vec<u8>)> = Vec::new();
-    if let Ok(attrs) = xattr::list(target) {
-        for attr_name in attrs {
-            if let Ok(Some(attr_value)) = xattr::get(target, &attr_name) {
-                target_xattrs.push((attr_name, attr_value));
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refused_xattrs_are_reported_without_failing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"attributed").unwrap();
+        let mut saved = SavedMetadata::capture(&path).unwrap();
+        // No filesystem accepts an attribute outside the known namespaces.
+        saved
+            .xattrs
+            .push((b"bogus.attr".to_vec(), b"value".to_vec()));
+        saved.mtime = (1_000_000_000, 0);
+
+        let lost = saved.apply(&path).unwrap();
+        assert_eq!(lost.len(), 1);
+        assert!(lost[0].contains("bogus.attr"), "{lost:?}");
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.mtime(), 1_000_000_000);
     }
 
-    let mut temp = target.to_path_buf();
-    temp.set_extension("imprint_tmp");
-    if temp.exists() {
-        std::fs::remove_file(&temp).with_context(|| "remove existing temp file")?;
+    /// Stages a replacement for a file holding `original` and returns the
+    /// directory, the target, the staged path and the original inode.
+    fn staged_replacement() -> (tempfile::TempDir, PathBuf, PathBuf, u64) {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file");
+        std::fs::write(&target, b"original").unwrap();
+        let temp = temp_path(&target);
+        std::fs::write(&temp, b"replacement").unwrap();
+        let ino = std::fs::metadata(&target).unwrap().ino();
+        (dir, target, temp, ino)
     }
 
-    let mut cleanup = TempCleanup::new(temp.clone());
-
-    {
-        let mut src = File::open(target).with_context(|| "open target for read")?;
-        let mut dst = File::create(&temp).with_context(|| "create temp file")?;
-        std::io::copy(&mut src, &mut dst).with_context(|| "copy bytes to temp file")?;
+    fn names(dir: &Path) -> Vec<std::ffi::OsString> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
     }
 
-    std::fs::rename(&temp, target).with_context(|| "replace target with restored copy")?;
-    cleanup.disarm();
+    #[test]
+    fn backed_up_swaps_roll_back_to_the_original() {
+        let (dir, target, temp, ino) = staged_replacement();
+        let swap = Swap::backed_up(&temp, &target).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"replacement");
 
-    apply_metadata(target, &target_permissions, target_mtime, &target_xattrs)?;
+        swap.rollback().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"original");
+        assert_eq!(std::fs::metadata(&target).unwrap().ino(), ino);
+        assert_eq!(names(dir.path()), ["file"]);
+    }
 
-    Ok(())
+    #[test]
+    fn backed_up_swaps_commit_without_leftovers() {
+        let (dir, target, temp, ino) = staged_replacement();
+        Swap::backed_up(&temp, &target).unwrap().commit().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"replacement");
+        assert_ne!(std::fs::metadata(&target).unwrap().ino(), ino);
+        assert_eq!(names(dir.path()), ["file"]);
+    }
+
+    #[test]
+    fn dropped_swaps_roll_back() {
+        let (dir, target, temp, ino) = staged_replacement();
+        drop(Swap::exchange(&temp, &target).unwrap());
+        assert_eq!(std::fs::read(&target).unwrap(), b"original");
+        assert_eq!(std::fs::metadata(&target).unwrap().ino(), ino);
+        assert_eq!(names(dir.path()), ["file"]);
+    }
 }
@@ -0,0 +1,361 @@
+use crate::hasher;
+use crate::scanner::ScannedFile;
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+use std::thread::JoinHandle;
+
+/// What a block device keeps its data on, as far as reading it goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Media {
+    /// Spinning disks, where every seek costs milliseconds.
+    Rotational,
+    /// SSDs and NVMe drives, which serve many reads at once.
+    SolidState,
+    /// Devices sysfs does not describe: network and virtual filesystems,
+    /// btrfs subvolumes (anonymous device numbers), other systems.
+    Unknown,
+}
+
+impl Media {
+    /// Reads `queue/rotational` of the disk behind `dev`, found through its
+    /// `/sys/dev/block` link. Partitions have no queue and use their disk's.
+    #[cfg(target_os = "linux")]
+    pub fn of(dev: u64) -> Self {
+        let link = format!(
+            "/sys/dev/block/{}:{}",
+            nix::libc::major(dev),
+            nix::libc::minor(dev)
+        );
+        let Ok(dir) = std::fs::canonicalize(link) else {
+            return Media::Unknown;
+        };
+        let disk = if dir.join("partition").exists() {
+            dir.parent().unwrap_or(&dir)
+        } else {
+            &dir
+        };
+        match std::fs::read_to_string(disk.join("queue").join("rotational")) {
+            Ok(flag) if flag.trim() == "1" => Media::Rotational,
+            Ok(flag) if flag.trim() == "0" => Media::SolidState,
+            _ => Media::Unknown,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn of(_dev: u64) -> Self {
+        Media::Unknown
+    }
+
+    /// How many files to hash at once on this media.
+    pub fn readers(self) -> usize {
+        let threads = rayon::current_num_threads();
+        match self {
+            // One file is read while the other is hashed; more readers only
+            // make the heads seek between them.
+            Media::Rotational => 2,
+            // Each reader keeps one request in flight, and flash drives only
+            // reach their speed with deep queues.
+            Media::SolidState => (threads * 2).clamp(8, 64),
+            Media::Unknown => threads.min(8),
+        }
+    }
+}
+
+/// Hands hash tasks to readers, with a queue and readers of its own for each
+/// device. Tasks for rotational devices go through a sorter thread that
+/// holds them in windows of `capacity` and releases them sorted by physical
+/// offset, sweeping up and down the disk like an elevator, so files are read
+/// in passes instead of by seeking. Offsets are looked up there, off the
+/// scan thread.
+pub struct Scheduler<F> {
+    queues: HashMap<u64, Sender<ScannedFile>>,
+    capacity: usize,
+    spawn: F,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<F: FnMut(Receiver<ScannedFile>) -> JoinHandle<()>> Scheduler<F> {
+    /// `spawn` starts a reader on the queue of one device.
+    pub fn new(capacity: usize, spawn: F) -> Self {
+        Self {
+            queues: HashMap::new(),
+            capacity,
+            spawn,
+            threads: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, file: ScannedFile) {
+        let dev = file.stamp.dev;
+        let tx = match self.queues.entry(dev) {
+            Entry::Occupied(queue) => queue.into_mut(),
+            Entry::Vacant(slot) => {
+                let media = Media::of(dev);
+                let (tx, rx) = channel::bounded(self.capacity);
+                for _ in 0..media.readers() {
+                    self.threads.push((self.spawn)(rx.clone()));
+                }
+                let tx = if media == Media::Rotational {
+                    let (sorter_tx, sorter_rx) = channel::bounded(self.capacity);
+                    let capacity = self.capacity;
+                    self.threads
+                        .push(std::thread::spawn(move || sort(sorter_rx, tx, capacity)));
+                    sorter_tx
+                } else {
+                    tx
+                };
+                slot.insert(tx)
+            }
+        };
+        let _ = tx.send(file);
+    }
+
+    /// Closes the queues and returns the sorters and readers, which stop
+    /// once the held tasks are released and the queues drained.
+    pub fn finish(self) -> Vec<JoinHandle<()>> {
+        drop(self.queues);
+        self.threads
+    }
+}
+
+/// How long a sorter waits for more tasks before releasing what it holds, so
+/// reads start while the walk is still slow to find files.
+const IDLE_RELEASE: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Passes the tasks received on `rx` to `tx` in elevator order, a window of
+/// `capacity` at a time, or whatever is held once `rx` has been idle for
+/// [`IDLE_RELEASE`].
+fn sort(rx: Receiver<ScannedFile>, tx: Sender<ScannedFile>, capacity: usize) {
+    let mut queue = Queue::default();
+    loop {
+        let full = match rx.recv_timeout(IDLE_RELEASE) {
+            Ok(file) => {
+                queue.held.push((
+                    first_extent(&file.path).map(|(_, physical, _)| physical),
+                    file,
+                ));
+                queue.held.len() >= capacity
+            }
+            Err(RecvTimeoutError::Timeout) => !queue.held.is_empty(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if full {
+            for file in queue.release() {
+                if tx.send(file).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+    for file in queue.release() {
+        let _ = tx.send(file);
+    }
+}
+
+/// The tasks a sorter holds, with the physical offset of each file.
+#[derive(Default)]
+struct Queue {
+    held: Vec<(Option<u64>, ScannedFile)>,
+    descending: bool,
+}
+
+impl Queue {
+    /// Takes the held tasks sorted by offset, in the opposite direction to
+    /// the previous window.
+    fn release(&mut self) -> Vec<ScannedFile> {
+        // Files without a stable mapping go last in either direction.
+        let descending = self.descending;
+        self.held.sort_by(|(a, _), (b, _)| match (a, b) {
+            (Some(a), Some(b)) if descending => b.cmp(a),
+            (Some(a), Some(b)) => a.cmp(b),
+            _ => a.is_none().cmp(&b.is_none()),
+        });
+        self.descending = !descending;
+        self.held.drain(..).map(|(_, file)| file).collect()
+    }
+}
+
+/// A physical extent: logical offset, physical offset and length.
+pub type Extent = (u64, u64, u64);
+
+/// The physical extents of `path`, or `None` when they cannot be compared:
+/// FIEMAP is unsupported, the file is empty, or some extent has no stable
+/// physical location yet (delayed allocation, inline data, ...). Dirty data
+/// is flushed first so every extent is mapped.
+pub fn extents(path: &Path) -> Option<Vec<Extent>> {
+    fiemap(path, true)
+}
+
+/// The first physical extent of `path`, as far as it is already mapped.
+/// Cheap enough to call for every file: nothing is flushed and only one
+/// extent is asked for.
+pub fn first_extent(path: &Path) -> Option<Extent> {
+    fiemap(path, false)?.first().copied()
+}
+
+#[cfg(target_os = "linux")]
+fn fiemap(path: &Path, all: bool) -> Option<Vec<Extent>> {
+    use std::os::unix::io::AsRawFd;
+
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    // Unknown location, delayed allocation, encoded, not aligned, inline data.
+    const FIEMAP_EXTENT_UNSTABLE: u32 = 0x2 | 0x4 | 0x8 | 0x100 | 0x200;
+    const BATCH: usize = 64;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; BATCH],
+    }
+
+    // The request code encodes the size of the header alone; the extent
+    // array after it is sized by `extent_count`.
+    nix::ioctl_readwrite_bad!(fiemap, nix::request_code_readwrite!(b'f', 11, 32), Fiemap);
+
+    let file = hasher::open_noatime(path).ok()?;
+    let mut found = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut request = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: if all { FIEMAP_FLAG_SYNC } else { 0 },
+            mapped_extents: 0,
+            extent_count: if all { BATCH as u32 } else { 1 },
+            reserved: 0,
+            extents: [FiemapExtent::default(); BATCH],
+        };
+        // SAFETY: `request` is a correctly laid out fiemap with room for
+        // `extent_count` extents, and the descriptor stays open.
+        unsafe { fiemap(file.as_raw_fd(), &mut request) }.ok()?;
+
+        let mapped = &request.extents[..request.mapped_extents as usize];
+        for extent in mapped {
+            if extent.flags & FIEMAP_EXTENT_UNSTABLE != 0 {
+                return None;
+            }
+            found.push((extent.logical, extent.physical, extent.length));
+        }
+        match mapped.last() {
+            Some(last) if all && last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            _ => break,
+        }
+    }
+    (!found.is_empty()).then_some(found)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fiemap(_path: &Path, _all: bool) -> Option<Vec<Extent>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change::FileStamp;
+    use std::path::PathBuf;
+
+    fn file(name: &str) -> ScannedFile {
+        ScannedFile {
+            path: PathBuf::from(name),
+            stamp: FileStamp {
+                dev: 1,
+                ino: 1,
+                size: 1,
+                mtime_ns: 0,
+                ctime_ns: 0,
+                nlink: 1,
+            },
+        }
+    }
+
+    fn release(queue: &mut Queue, held: &[(Option<u64>, &str)]) -> Vec<PathBuf> {
+        queue.held = held
+            .iter()
+            .map(|&(offset, name)| (offset, file(name)))
+            .collect();
+        queue.release().into_iter().map(|file| file.path).collect()
+    }
+
+    #[test]
+    fn release_alternates_direction_with_unmapped_files_last() {
+        let mut queue = Queue::default();
+        let held = [
+            (Some(30), "c"),
+            (None, "x"),
+            (Some(10), "a"),
+            (Some(20), "b"),
+            (None, "y"),
+        ];
+        assert_eq!(
+            release(&mut queue, &held),
+            ["a", "b", "c", "x", "y"].map(PathBuf::from)
+        );
+        assert_eq!(
+            release(&mut queue, &held),
+            ["c", "b", "a", "x", "y"].map(PathBuf::from)
+        );
+        assert_eq!(
+            release(&mut queue, &held),
+            ["a", "b", "c", "x", "y"].map(PathBuf::from)
+        );
+        assert!(queue.held.is_empty());
+    }
+
+    #[test]
+    fn sorter_releases_a_partial_window_when_idle() {
+        let (tx, rx) = channel::unbounded();
+        let (sorted_tx, sorted_rx) = channel::unbounded();
+        let sorter = std::thread::spawn(move || sort(rx, sorted_tx, 4096));
+        for name in ["a", "b", "c"] {
+            tx.send(file(name)).unwrap();
+        }
+        // The walk is still going: the sender is open and the window far
+        // from full.
+        let timeout = std::time::Duration::from_secs(5);
+        for _ in 0..3 {
+            assert!(sorted_rx.recv_timeout(timeout).is_ok());
+        }
+        drop(tx);
+        sorter.join().unwrap();
+        assert!(sorted_rx.recv().is_err());
+    }
+
+    #[test]
+    fn readers_follow_media() {
+        let readers = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| {
+                    [Media::Rotational, Media::SolidState, Media::Unknown].map(Media::readers)
+                })
+        };
+        assert_eq!(readers(1), [2, 8, 1]);
+        assert_eq!(readers(4), [2, 8, 4]);
+        assert_eq!(readers(16), [2, 32, 8]);
+        assert_eq!(readers(64), [2, 64, 8]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+/// Failures that callers need to tell apart, each with a stable exit code.
+///
+/// Errors tied to a single file are collected during a run and reported at
+/// the end instead of aborting; only database errors stop a run outright.
+#[derive(Debug, thiserror::Error)]
+pub enum BdError {
+    #[error("{path:?}: reflink not supported and no fallback strategy succeeded")]
+    ReflinkUnsupported {
+        path: PathBuf,
+        #[source]
+        source: Option<anyhow::Error>,
+    },
+    #[error("{path:?}: no link strategy applies (tried {strategies})")]
+    NoUsableStrategy { path: PathBuf, strategies: String },
+    #[error("{path:?}: replaced, but could not preserve {}", lost.join(", "))]
+    MetadataLost { path: PathBuf, lost: Vec<String> },
+    #[error("{path:?}: file changed during operation ({reason})")]
+    FileChanged { path: PathBuf, reason: String },
+    #[error("{path:?}: permission denied")]
+    PermissionDenied {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("{path:?}: I/O error")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("{path:?}: content does not match its hash (collision or bit rot)")]
+    HashMismatch { path: PathBuf },
+    #[error("{path:?}: vault I/O failed")]
+    VaultIo {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("state database error")]
+    Db(#[source] anyhow::Error),
+    #[error("{skipped} entries could not be scanned (--strict)")]
+    ScanIncomplete { skipped: u64 },
+    #[error("{path:?}: not enough free space (needs {needed} bytes, {available} available)")]
+    NoSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+}
+
+impl BdError {
+    /// Exit code for this error. When a run hits several kinds of failure the
+    /// highest code is used, so codes grow with severity.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BdError::ReflinkUnsupported { .. } => 10,
+            BdError::FileChanged { .. } => 11,
+            BdError::PermissionDenied { .. } => 12,
+            BdError::Io { .. } => 13,
+            BdError::HashMismatch { .. } => 14,
+            BdError::VaultIo { .. } => 15,
+            BdError::Db(_) => 16,
+            BdError::ScanIncomplete { .. } => 17,
+            BdError::NoSpace { .. } => 18,
+            BdError::NoUsableStrategy { .. } => 19,
+            BdError::MetadataLost { .. } => 20,
+        }
+    }
+
+    /// Wraps a file operation failure, picking out permission errors. A
+    /// failure that already is a `BdError` is passed through unchanged.
+    pub fn file(path: &Path, source: anyhow::Error) -> Self {
+        let source = match source.downcast::<BdError>() {
+            Ok(err) => return err,
+            Err(source) => source,
+        };
+        if is_permission_denied(&source) {
+            BdError::PermissionDenied {
+                path: path.to_path_buf(),
+                source,
+            }
+        } else {
+            BdError::Io {
+                path: path.to_path_buf(),
+                source,
+            }
+        }
+    }
+}
+
+pub fn is_permission_denied(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|io| io.kind() == std::io::ErrorKind::PermissionDenied)
+    })
+}
+
+/// Whether `err` comes from the filesystem or kernel lacking an operation,
+/// rather than from the operation itself failing.
+#[cfg(target_os = "linux")]
+pub fn is_unsupported(err: &anyhow::Error) -> bool {
+    use nix::errno::Errno;
+
+    err.chain().any(|cause| {
+        let errno = cause.downcast_ref::<Errno>().copied().or_else(|| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::raw_os_error)
+                .map(Errno::from_i32)
+        });
+        matches!(
+            errno,
+            Some(Errno::EOPNOTSUPP | Errno::ENOTTY | Errno::EXDEV | Errno::EINVAL | Errno::ENOSYS)
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_unsupported(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+
+    err.chain().any(|cause| {
+        cause.downcast_ref::<std::io::Error>().is_some_and(|io| {
+            matches!(
+                io.kind(),
+                ErrorKind::Unsupported | ErrorKind::CrossesDevices | ErrorKind::InvalidInput
+            )
+        })
+    })
+}
+
+/// Exit code for an error that ended the run.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.downcast_ref::<BdError>()
+        .map(BdError::exit_code)
+        .unwrap_or(1)
+}
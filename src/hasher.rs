@@ -8,12 +8,31 @@ const SPARSE_CHUNK: usize = 4 * 1024;
 const SPARSE_TOTAL: u64 = 12 * 1024;
 const FULL_BUF: usize = 128 * 1024;
 
+/// Opens `path` for reading without updating its access time, so hashing and
+/// comparing leave the original atime in place. Falls back to a plain open
+/// when `O_NOATIME` is not permitted (only the owner may use it).
+pub fn open_noatime(path: &Path) -> std::io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if let Ok(file) = File::options()
+            .read(true)
+            .custom_flags(nix::libc::O_NOATIME)
+            .open(path)
+        {
+            return Ok(file);
+        }
+    }
+    File::open(path)
+}
+
 pub fn sparse_hash(path: &Path, size: u64) -> Result<Hash> {
     if size <= SPARSE_TOTAL {
         return full_hash(path);
     }
 
-    let mut file = File::open(path).with_context(|| format!("open file {:?}", path))?;
+    let mut file = open_noatime(path).with_context(|| format!("open file {:?}", path))?;
     let mut hasher = blake3::Hasher::new();
 
     let mut buffer = vec![0u8; SPARSE_CHUNK];
@@ -34,7 +53,7 @@ pub fn sparse_hash(path: &Path, size: u64) -> Result<Hash> {
 }
 
 pub fn full_hash(path: &Path) -> Result<Hash> {
-    let mut file = File::open(path).with_context(|| format!("open file {:?}", path))?;
+    let mut file = open_noatime(path).with_context(|| format!("open file {:?}", path))?;
     let mut hasher = blake3::Hasher::new();
     let mut buffer = vec![0u8; FULL_BUF];
 
@@ -1,7 +1,19 @@
+mod change;
 mod dedupe;
+mod device;
+mod error;
 mod hasher;
+mod migrate;
+mod rebuild;
+mod run;
 mod scanner;
+mod scrub;
+mod skip;
+mod spill;
+mod staging;
 mod state;
+mod strategy;
+mod trash;
 mod types;
 mod vault;
 
@@ -10,12 +22,19 @@ use clap::{Parser, Subcommand};
 use colored::*;
 use crossbeam::channel;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
+use crate::change::FileStamp;
+use crate::error::BdError;
+use crate::scanner::ScannedFile;
 use crate::state::DbOp;
-use crate::types::{FileMetadata, Hash};
+use crate::strategy::LinkStrategy;
+use crate::types::{FileMetadata, Hash, InodeId};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -24,7 +43,7 @@ use crate::types::{FileMetadata, Hash};
     version,
     about = "bdstorage: A speed-first, local file deduplication engine.",
     long_about = "bdstorage uses a Tiered Hashing philosophy to minimize I/O overhead:\n\nSize Grouping: Eliminates unique file sizes immediately.\n\nSparse Hashing: Samples 12KB (start/middle/end) to identify candidates.\n\nFull BLAKE3 Hashing: Verifies matches with high-performance 128KB buffering.",
-    help_template = "{before-help}{name} {version}\n{author-with-newline}{about-section}\n\nSTORAGE PATHS:\n  State DB: ~/.bdstorage/state.redb\n  CAS Vault: ~/.bdstorage/store\n\n{usage-heading} {usage}\n\nGLOBAL FLAGS:\n  -h, --help     Print help\n  -V, --version  Print version\n\nSUBCOMMAND FLAGS:\n  --paranoid                 Available on the dedupe subcommand. Forces a byte-for-byte\n                             verification before linking to guarantee 100% collision safety.\n\n  --allow-unsafe-hardlinks   Available on the dedupe subcommand. Allows hard link fallback\n                             when CoW reflinks are not supported. Hard links share the same\n                             inode, so all linked files will have identical metadata.\n\n  -n, --dry-run              Available on dedupe and restore subcommands. Simulates operations\n                             without modifying the filesystem or the database.\n\n{all-args}{after-help}"
+    help_template = "{before-help}{name} {version}\n{author-with-newline}{about-section}\n\nSTORAGE PATHS:\n  State DB: ~/.bdstorage/state.redb\n  CAS Vault: ~/.bdstorage/store\n\n{usage-heading} {usage}\n\nGLOBAL FLAGS:\n  -h, --help     Print help\n  -V, --version  Print version\n\nSUBCOMMAND FLAGS:\n  --memory-limit <MB>        Available on scan and dedupe. Moves the size and hash groupings and\n                             hard link names to temporary databases once they would use more\n                             memory than this.\n\n  --paranoid                 Available on the dedupe subcommand. Forces a byte-for-byte\n                             verification before linking to guarantee 100% collision safety.\n\n  --allow-unsafe-hardlinks   Available on the dedupe subcommand. Allows hard link fallback\n                             when CoW reflinks are not supported. Hard links share the same\n                             inode, so all linked files will have identical metadata.\n\n  --strategy <LIST>          Available on the dedupe subcommand. Comma-separated fallback chain\n                             tried in order: reflink, dedupe-range, hardlink, symlink,\n                             relative-symlink, copy.\n                             Defaults to reflink (plus hardlink with --allow-unsafe-hardlinks).\n\n  --mode <MODE>              Available on the dedupe subcommand. How duplicates are resolved:\n                             link (default), symlink, absolute-symlink or delete.\n\n  --trash <DIR>              Available on dedupe (with --mode delete) and restore. Moves deleted\n                             duplicates to DIR with a manifest; restore moves them back.\n\n  --external-links <POLICY>  Available on the dedupe subcommand. Files with hard links outside\n                             PATH: skip (default), relink-all or break.\n\n  -j, --jobs <N>             Available on the dedupe subcommand. Links up to N duplicate groups\n                             at once. Defaults to one worker per CPU.\n\n  --glob <PATTERN>           Available on the restore subcommand. Only restores files whose path\n                             relative to PATH matches PATTERN.\n\n  --hash <HASH>              Available on the restore subcommand. Restores every file with this\n                             content hash.\n\n  --run <ID>                 Available on the restore subcommand. Restores every file linked by\n                             the dedupe run with this ID (printed by dedupe).\n\n  --limit <N>                Available on the scrub subcommand. Checks at most N vault objects,\n                             resuming where the previous limited scrub stopped.\n\n  --max-rate <MB_PER_SEC>    Available on the scrub subcommand. Throttles vault reads.\n\n  -n, --dry-run              Available on dedupe, restore, scrub, undo and rebuild-state. Simulates operations\n                             without modifying the filesystem or the database.\n\n{all-args}{after-help}"
 )]
 struct Args {
     #[command(subcommand)]
@@ -35,68 +54,375 @@ struct Args {
 enum Commands {
     Scan {
         path: PathBuf,
+        #[arg(long, value_name = "FILE")]
+        errors_log: Option<PathBuf>,
+        #[arg(long)]
+        strict: bool,
+        #[arg(long, value_name = "MB")]
+        memory_limit: Option<u64>,
     },
     Dedupe {
         path: PathBuf,
+        #[arg(long, value_name = "FILE")]
+        errors_log: Option<PathBuf>,
+        #[arg(long)]
+        strict: bool,
+        #[arg(long, value_name = "MB")]
+        memory_limit: Option<u64>,
         #[arg(long)]
         paranoid: bool,
         #[arg(long, short = 'n')]
         dry_run: bool,
         #[arg(long, action = clap::ArgAction::SetTrue, default_value_t = false)]
         allow_unsafe_hardlinks: bool,
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "LIST")]
+        strategy: Vec<strategy::StrategyKind>,
+        #[arg(long, value_enum, default_value_t = dedupe::DedupeMode::Link)]
+        mode: dedupe::DedupeMode,
+        #[arg(long, value_name = "DIR")]
+        trash: Option<PathBuf>,
+        #[arg(long, value_enum, value_name = "POLICY", default_value_t = dedupe::ExternalLinks::Skip)]
+        external_links: dedupe::ExternalLinks,
+        #[arg(long, short = 'j', value_name = "N")]
+        jobs: Option<std::num::NonZeroUsize>,
     },
     Restore {
-        path: PathBuf,
+        #[arg(required_unless_present_any = ["hash", "run"])]
+        path: Option<PathBuf>,
+        #[arg(long, value_name = "PATTERN", conflicts_with_all = ["hash", "run"])]
+        glob: Option<String>,
+        #[arg(long, value_name = "HASH", conflicts_with = "run")]
+        hash: Option<String>,
+        #[arg(long, value_name = "ID")]
+        run: Option<u64>,
+        #[arg(long, short = 'n')]
+        dry_run: bool,
+        #[arg(long, value_name = "DIR")]
+        trash: Option<PathBuf>,
+    },
+    Scrub {
+        #[arg(long, value_name = "N")]
+        limit: Option<u64>,
+        #[arg(long, value_name = "MB_PER_SEC")]
+        max_rate: Option<u64>,
+        #[arg(long, short = 'n')]
+        dry_run: bool,
+    },
+    RebuildState {
+        #[arg(required = true)]
+        roots: Vec<PathBuf>,
+        #[arg(long, short = 'n')]
+        dry_run: bool,
+    },
+    History,
+    Undo {
+        run: u64,
         #[arg(long, short = 'n')]
         dry_run: bool,
     },
 }
 
 fn main() {
-    if let Err(err) = run() {
-        eprintln!("{err:?}");
-        std::process::exit(1);
+    match run() {
+        Ok(failures) if failures.is_empty() => {}
+        Ok(failures) => {
+            report_failures(&failures);
+            let code = failures.iter().map(BdError::exit_code).max().unwrap_or(1);
+            std::process::exit(code);
+        }
+        Err(err) => {
+            eprintln!("{err:?}");
+            std::process::exit(error::exit_code(&err));
+        }
     }
 }
 
-fn run() -> Result<()> {
+fn run() -> Result<Vec<BdError>> {
     let args = Args::parse();
+    let mut failures = Vec::new();
 
     match args.command {
-        Commands::Scan { path } => {
-            let state = state::State::open_default()?;
-            let groups = scan_pipeline(&path, &state)?;
-            print_summary("scan", &groups);
+        Commands::Scan {
+            path,
+            errors_log,
+            strict,
+            memory_limit,
+        } => {
+            let state = open_state(false)?;
+            let skips = skip::SkipLog::new(errors_log.as_deref())?;
+            let scan = scan_pipeline(&path, &state, &skips, memory_limit)?;
+            finish_scan(&skips, strict)?;
+            let summary = stream_groups(scan, |groups| {
+                let refcounts = groups
+                    .iter()
+                    .map(|group| DbOp::SetCasRefcount(group.hash, group.paths.len() as u64))
+                    .collect();
+                state.batch_write(refcounts).map_err(BdError::Db)?;
+                Ok(())
+            })?;
+            print_summary("scan", &summary);
         }
         Commands::Dedupe {
             path,
+            errors_log,
+            strict,
+            memory_limit,
             paranoid,
             dry_run,
             allow_unsafe_hardlinks,
+            strategy,
+            mode,
+            trash,
+            external_links,
+            jobs,
         } => {
-            let state = if dry_run {
-                state::State::open_readonly_if_exists()?
-            } else {
-                state::State::open_default()?
+            if trash.is_some() && mode != dedupe::DedupeMode::Delete {
+                anyhow::bail!("--trash can only be used with --mode delete");
+            }
+            // Files are leased while they are replaced, and a writer opening
+            // one sends SIGIO. Nothing in this process uses SIGIO otherwise, so
+            // it is ignored for the whole run rather than around each lease.
+            change::ignore_lease_breaks();
+            let state = open_state(dry_run)?;
+            let mut trash = match trash {
+                Some(dir) if !dry_run => Some(trash::Trash::open(&dir)?),
+                _ => None,
             };
-            let groups = scan_pipeline(&path, &state)?;
-            dedupe_groups(&groups, &state, paranoid, dry_run, allow_unsafe_hardlinks)?;
-            print_summary("dedupe", &groups);
-        }
-        Commands::Restore { path, dry_run } => {
-            let state = if dry_run {
-                state::State::open_readonly_if_exists()?
+            let mut kinds = if strategy.is_empty() {
+                vec![strategy::StrategyKind::Reflink]
             } else {
-                state::State::open_default()?
+                strategy
+            };
+            if allow_unsafe_hardlinks {
+                kinds.push(strategy::StrategyKind::Hardlink);
+            }
+            let chain = strategy::LinkChain::new(&kinds);
+            let skips = skip::SkipLog::new(errors_log.as_deref())?;
+            let scan = scan_pipeline(&path, &state, &skips, memory_limit)?;
+            finish_scan(&skips, strict)?;
+            let options = DedupeOptions {
+                paranoid,
+                dry_run,
+                chain: &chain,
+                mode,
+                external_links,
             };
-            restore_pipeline(&path, &state, dry_run)?;
+            // Zero threads lets rayon use one per CPU.
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs.map_or(0, std::num::NonZeroUsize::get))
+                .build()
+                .with_context(|| "start dedupe workers")?;
+            let summary;
+            (failures, summary) =
+                pool.install(|| dedupe_groups(scan, &state, &options, trash.as_mut()))?;
+            print_summary("dedupe", &summary);
+        }
+        Commands::Restore {
+            path,
+            glob,
+            hash,
+            run,
+            dry_run,
+            trash,
+        } => {
+            let selection = RestoreSelection::from_args(path, glob, hash, run)?;
+            let state = open_state(dry_run)?;
+            if let Some(dir) = trash {
+                restore_trash(&dir, dry_run)?;
+            }
+            failures = restore_pipeline(&selection, &state, dry_run)?;
+        }
+        Commands::Scrub {
+            limit,
+            max_rate,
+            dry_run,
+        } => {
+            let state = open_state(dry_run)?;
+            failures = scrub_pipeline(&state, limit, max_rate, dry_run)?;
+        }
+        Commands::RebuildState { roots, dry_run } => {
+            failures = rebuild_state(&roots, dry_run)?;
+        }
+        Commands::History => {
+            let state = open_state(true)?;
+            print_history(&state)?;
+        }
+        Commands::Undo { run, dry_run } => {
+            let state = open_state(dry_run)?;
+            failures = undo_run(run, &state, dry_run)?;
         }
     }
 
+    Ok(failures)
+}
+
+/// Opens the state database, or for `readonly` commands a view of it that
+/// is never written back. Commands that write first move legacy state to
+/// its current location. Either kind of upgrade is reported.
+fn open_state(readonly: bool) -> Result<state::State> {
+    if !readonly {
+        relocate_legacy_state()?;
+    }
+    let state = if readonly {
+        state::State::open_readonly_if_exists()
+    } else {
+        state::State::open_default()
+    }
+    .map_err(BdError::Db)?;
+    if let Some(upgrade) = state.upgrade() {
+        match &upgrade.backup {
+            Some(backup) => eprintln!(
+                "{} Upgraded the state database from schema version {} to {}; the previous database was saved as {}",
+                "[MIGRATED]".bold().cyan(),
+                upgrade.from,
+                upgrade.to,
+                backup.display()
+            ),
+            None => eprintln!(
+                "{} The state database uses schema version {}; reading an upgraded copy and leaving it unchanged",
+                "[MIGRATED]".bold().cyan(),
+                upgrade.from
+            ),
+        }
+    }
+    Ok(state)
+}
+
+fn relocate_legacy_state() -> Result<()> {
+    if let Some(legacy) = migrate::relocate_legacy().map_err(BdError::Db)? {
+        eprintln!(
+            "{} Moved {} to {}",
+            "[MIGRATED]".bold().cyan(),
+            legacy.display(),
+            state::data_dir()?.display()
+        );
+    }
     Ok(())
 }
 
-fn scan_pipeline(path: &Path, state: &state::State) -> Result<HashMap<Hash, Vec<PathBuf>>> {
+fn report_failures(failures: &[BdError]) {
+    eprintln!(
+        "\n{} {} file(s) could not be processed:",
+        "[ERROR]".bold().red(),
+        failures.len()
+    );
+    for failure in failures {
+        match std::error::Error::source(failure) {
+            Some(source) => eprintln!("  {failure}: {source}"),
+            None => eprintln!("  {failure}"),
+        }
+    }
+}
+
+/// Files grouped by content hash, with the stamp each one had when hashed.
+/// The groups are read back one at a time by [`stream_groups`].
+struct ScanResult {
+    root: PathBuf,
+    duplicates: spill::Duplicates,
+    links: spill::HardLinkIndex,
+}
+
+/// One duplicate group of a scan, with what linking it needs.
+struct Group {
+    hash: Hash,
+    /// The files of the group, master first.
+    paths: Vec<PathBuf>,
+    /// The stamp each file, and each of its aliases, had when it was scanned.
+    stamps: HashMap<PathBuf, FileStamp>,
+    /// The other names under the root of a hard linked file, keyed by the
+    /// name it was hashed under.
+    aliases: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Files that also have names outside the root.
+    linked_outside: HashSet<PathBuf>,
+    /// The metadata each file had before the run touched it.
+    originals: HashMap<PathBuf, dedupe::SavedMetadata>,
+}
+
+impl Group {
+    fn new(hash: Hash, files: Vec<ScannedFile>, links: &spill::HardLinkIndex) -> Result<Self> {
+        let mut group = Self {
+            hash,
+            paths: Vec::with_capacity(files.len()),
+            stamps: HashMap::new(),
+            aliases: HashMap::new(),
+            linked_outside: HashSet::new(),
+            originals: HashMap::new(),
+        };
+        for ScannedFile { path, stamp } in files {
+            let (aliases, outside) = links.names(&stamp)?;
+            for alias in &aliases {
+                group.stamps.insert(alias.clone(), stamp);
+            }
+            if !aliases.is_empty() {
+                group.aliases.insert(path.clone(), aliases);
+            }
+            if outside {
+                group.linked_outside.insert(path.clone());
+            }
+            group.stamps.insert(path.clone(), stamp);
+            group.paths.push(path);
+        }
+        Ok(group)
+    }
+}
+
+/// What a scan found: its duplicate groups and the bytes linking them frees.
+#[derive(Default)]
+struct Summary {
+    groups: u64,
+    reclaimable: u64,
+}
+
+/// Duplicate groups are read back and linked this many at a time.
+const GROUP_CHUNK: usize = 1024;
+
+/// Reads the duplicate groups of `scan` back in chunks of [`GROUP_CHUNK`]
+/// and passes each chunk to `each`.
+fn stream_groups(
+    scan: ScanResult,
+    mut each: impl FnMut(&mut Vec<Group>) -> Result<()>,
+) -> Result<Summary> {
+    let ScanResult {
+        duplicates, links, ..
+    } = scan;
+    let mut summary = Summary::default();
+    let mut chunk = Vec::with_capacity(GROUP_CHUNK);
+    let mut flush = |chunk: &mut Vec<Group>| -> Result<()> {
+        each(chunk)?;
+        chunk.clear();
+        Ok(())
+    };
+    duplicates.for_each(|hash, files| {
+        let group = Group::new(hash, files, &links)?;
+        summary.groups += 1;
+        summary.reclaimable += reclaimable_bytes(&group);
+        chunk.push(group);
+        if chunk.len() >= GROUP_CHUNK {
+            flush(&mut chunk)?;
+        }
+        Ok(())
+    })?;
+    flush(&mut chunk)?;
+    Ok(summary)
+}
+
+/// How many entries each stage of the scan may queue for the next one
+/// before it waits.
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// Scans `path`, hashing files that share their size with another. With a
+/// `memory_limit` in MB, the size and hash groupings and the hard link names
+/// move to disk once they would use more than that.
+fn scan_pipeline(
+    path: &Path,
+    state: &state::State,
+    skips: &skip::SkipLog,
+    memory_limit: Option<u64>,
+) -> Result<ScanResult> {
+    // Paths are indexed under the canonical root however it was spelled, so
+    // `./data` and `/home/me/data` (or a symlink to it) share their records.
+    let root = std::fs::canonicalize(path).with_context(|| format!("resolve {path:?}"))?;
+    let path = root.as_path();
     let multi = MultiProgress::new();
     let scan_spinner = multi.add(ProgressBar::new_spinner());
     scan_spinner.set_style(
@@ -108,385 +434,1312 @@ fn scan_pipeline(path: &Path, state: &state::State) -> Result<HashMap<Hash, Vec<
 
     let hash_bar = multi.add(progress("Indexing/Hashing", 0));
 
-    let (scan_tx, scan_rx) = channel::unbounded();
+    let budget = spill::Budget::new(memory_limit.map(|mb| mb * 1_048_576));
+    let (scan_tx, scan_rx) = channel::bounded(CHANNEL_CAPACITY);
     let path_clone = path.to_path_buf();
-    let scanner_handle =
-        std::thread::spawn(move || -> Result<()> { scanner::stream_scan(&path_clone, scan_tx) });
+    let scanner_skips = skips.clone();
+    let scanner_handle = std::thread::spawn(move || -> Result<()> {
+        scanner::stream_scan(&path_clone, scan_tx, &scanner_skips)
+    });
 
-    let (hash_task_tx, hash_task_rx) = channel::unbounded::<PathBuf>();
+    let (result_tx, result_rx) = channel::bounded::<(Hash, ScannedFile)>(CHANNEL_CAPACITY);
 
-    let (result_tx, result_rx) = channel::unbounded::<(Hash, PathBuf)>();
+    let (db_tx, db_rx) = channel::bounded::<DbOp>(CHANNEL_CAPACITY);
 
-    let (db_tx, db_rx) = channel::unbounded::<DbOp>();
+    // Results are grouped as they arrive, so workers never wait on a full
+    // result channel while the scan waits on them.
+    let collector_root = path.to_path_buf();
+    let collector_budget = budget.clone();
+    let collector_handle = std::thread::spawn(move || -> Result<spill::Duplicates> {
+        let mut groups = spill::HashGroups::new(&collector_root, collector_budget);
+        while let Ok((hash, file)) = result_rx.recv() {
+            groups.add(hash, file)?;
+        }
+        groups.finish()
+    });
 
     let state_clone = state.clone();
-    let num_workers = std::cmp::min(rayon::current_num_threads(), 8);
-    let mut worker_handles = vec![];
-
-    for _ in 0..num_workers {
-        let rx = hash_task_rx.clone();
-        let tx = result_tx.clone();
-        let db_ops_tx = db_tx.clone();
-        let state_ref = state_clone.clone();
-        let hash_bar_clone = hash_bar.clone();
-
-        let handle = std::thread::spawn(move || {
-            while let Ok(file_path) = rx.recv() {
-                if let Ok(metadata) = std::fs::metadata(&file_path) {
-                    let inode = metadata.ino();
-                    if let Ok(is_vaulted) = state_ref.is_inode_vaulted(inode)
+    // Each device gets as many readers as its media handles well.
+    let mut scheduler =
+        device::Scheduler::new(CHANNEL_CAPACITY, |rx: channel::Receiver<ScannedFile>| {
+            let tx = result_tx.clone();
+            let db_ops_tx = db_tx.clone();
+            let state_ref = state_clone.clone();
+            let hash_bar_clone = hash_bar.clone();
+            let worker_skips = skips.clone();
+
+            std::thread::spawn(move || {
+                while let Ok(file) = rx.recv() {
+                    let stamp = file.stamp;
+                    if let Ok(is_vaulted) = state_ref.is_inode_vaulted(stamp.inode())
                         && is_vaulted
                     {
                         continue;
                     }
 
-                    let size = metadata.len();
-                    if let Ok(_) = hasher::sparse_hash(&file_path, size)
-                        && let Ok(full_hash) = hasher::full_hash(&file_path)
-                    {
-                        let modified = file_modified(&file_path).unwrap_or(0);
-                        let file_metadata = FileMetadata {
-                            size,
-                            modified,
-                            hash: full_hash,
-                        };
-                        let _ = db_ops_tx.send(DbOp::UpsertFile(file_path.clone(), file_metadata));
-                        let _ = tx.send((full_hash, file_path));
-                        hash_bar_clone.inc(1);
+                    // The scan's stamp is not checked again here: a file
+                    // written to since it was scanned, or while it was read,
+                    // no longer matches it when it is linked and is skipped
+                    // there.
+                    let hashed = hasher::sparse_hash(&file.path, stamp.size)
+                        .and_then(|_| hasher::full_hash(&file.path));
+                    match hashed {
+                        Ok(full_hash) => {
+                            let file_metadata = FileMetadata {
+                                size: stamp.size,
+                                modified: (stamp.mtime_ns / 1_000_000_000) as u64,
+                                hash: full_hash,
+                                inode: Some(stamp.inode()),
+                            };
+                            let _ =
+                                db_ops_tx.send(DbOp::UpsertFile(file.path.clone(), file_metadata));
+                            let _ = tx.send((full_hash, file));
+                        }
+                        Err(err) => worker_skips.record(&file.path, Some(stamp.size), &err),
                     }
+                    hash_bar_clone.inc(1);
                 }
-            }
+            })
         });
 
-        worker_handles.push(handle);
-    }
-
     let state_db_writer = state_clone.clone();
-    let db_writer_handle = std::thread::spawn(move || {
-        state_db_writer.batch_write_from_channel(db_rx);
-    });
+    let db_writer_handle =
+        std::thread::spawn(move || state_db_writer.batch_write_from_channel(db_rx));
 
-    let mut size_map: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut sizes = spill::SizeIndex::new(path, budget.clone());
+    let mut links = spill::HardLinks::new(path, budget);
 
-    while let Ok(file_path) = scan_rx.recv() {
+    while let Ok(file) = scan_rx.recv() {
         scan_spinner.tick();
 
-        if let Ok(metadata) = std::fs::metadata(&file_path) {
-            let size = metadata.len();
-            let entry = size_map.entry(size).or_default();
-            let len_before = entry.len();
-            entry.push(file_path.clone());
-
-            if len_before == 1 {
-                if let Some(first_file) = entry.first().cloned() {
-                    let _ = hash_task_tx.send(first_file);
-                }
-                let _ = hash_task_tx.send(file_path);
-                hash_bar.set_length(hash_bar.length().unwrap_or(0) + 2);
-            } else if len_before > 1 {
-                let _ = hash_task_tx.send(file_path);
-                hash_bar.set_length(hash_bar.length().unwrap_or(0) + 1);
-            }
+        // A file indexed under a name that is gone was moved or renamed;
+        // its records follow it. Looked up by the writer along with the rest
+        // of its batch, and queued before the file is hashed, so a new record
+        // for it lands after the move.
+        let _ = db_tx.send(DbOp::FollowMove(file.path.clone(), file.stamp));
+        // Names of one file are hashed once, under the first name found.
+        let Some(file) = links.add(file)? else {
+            continue;
+        };
+        let tasks = sizes.add(file)?;
+        hash_bar.set_length(hash_bar.length().unwrap_or(0) + tasks.len() as u64);
+        for task in tasks {
+            scheduler.push(task);
         }
     }
+    drop(sizes);
 
     scan_spinner.finish_and_clear();
 
     let _ = scanner_handle.join();
 
-    drop(hash_task_tx);
-
-    for handle in worker_handles {
+    for handle in scheduler.finish() {
         let _ = handle.join();
     }
 
     drop(result_tx);
     drop(db_tx);
 
-    let mut results: HashMap<Hash, Vec<PathBuf>> = HashMap::new();
-    while let Ok((hash, path)) = result_rx.recv() {
-        results.entry(hash).or_default().push(path);
-    }
+    let duplicates = collector_handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("result collector panicked"))??;
 
     hash_bar.finish_and_clear();
 
-    let _ = db_writer_handle.join();
+    db_writer_handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("state writer panicked"))?
+        .map_err(BdError::Db)?;
+
+    Ok(ScanResult {
+        root: path.to_path_buf(),
+        duplicates,
+        links: links.finish()?,
+    })
+}
+
+/// How a dedupe links its duplicate groups, as given on the command line.
+struct DedupeOptions<'a> {
+    paranoid: bool,
+    dry_run: bool,
+    chain: &'a strategy::LinkChain,
+    mode: dedupe::DedupeMode,
+    external_links: dedupe::ExternalLinks,
+}
+
+/// The settings and shared state every duplicate group of a dedupe is
+/// linked with.
+struct Linker<'a> {
+    paranoid: bool,
+    dry_run: bool,
+    chain: &'a strategy::LinkChain,
+    mode: dedupe::DedupeMode,
+    trash: Mutex<Option<&'a mut trash::Trash>>,
+    recorder: run::Recorder,
+}
+
+/// What linking one duplicate group produced. Its output is printed in one
+/// piece once the group is done, so groups linked in parallel do not
+/// interleave.
+#[derive(Default)]
+struct GroupOutcome {
+    output: Vec<String>,
+    db_ops: Vec<DbOp>,
+    failures: Vec<BdError>,
+    /// Files skipped because the filesystem cannot reflink them.
+    reflink_unsupported: Vec<String>,
+}
 
-    let mut refcount_ops = Vec::new();
-    for (hash, paths) in &results {
-        if paths.len() > 1 {
-            refcount_ops.push(DbOp::SetCasRefcount(*hash, paths.len() as u64));
+impl GroupOutcome {
+    fn print(&self) {
+        static REFLINK_WARNING: std::sync::Once = std::sync::Once::new();
+        let mut stdout = std::io::stdout().lock();
+        for line in &self.output {
+            let _ = writeln!(stdout, "{line}");
+        }
+        for name in &self.reflink_unsupported {
+            REFLINK_WARNING.call_once(|| print_reflink_warning(&mut stdout));
+            let _ = writeln!(stdout, "{} {}", "[SKIPPED]".bold().red(), name);
         }
     }
-    if !refcount_ops.is_empty() {
-        state.batch_write(refcount_ops)?;
-    }
-
-    Ok(results)
 }
 
+/// Links the duplicate groups of `scan` as they are read back, several at a
+/// time on the current rayon pool. Their database updates go through one
+/// writer thread.
 fn dedupe_groups(
-    groups: &HashMap<Hash, Vec<PathBuf>>,
+    scan: ScanResult,
     state: &state::State,
-    paranoid: bool,
-    dry_run: bool,
-    allow_unsafe_hardlinks: bool,
-) -> Result<()> {
-    let mut global_db_ops = Vec::new();
-    let mut reflink_warning_shown = false;
-    let mut warn_reflink_unsupported = |name: &str| {
-        if !reflink_warning_shown {
-            println!("\n{}", "━".repeat(80).yellow());
-            println!(
-                "{} Filesystem Does Not Support Copy-on-Write Reflinks",
-                "[WARNING]".bold().yellow()
-            );
-            println!("{}", "━".repeat(80).yellow());
-            println!("\nYour filesystem does not support CoW (Copy-on-Write) reflinks.");
-            println!(
-                "Reflinks allow files to share disk space while remaining independent copies."
-            );
-            println!(
-                "When you modify a reflinked file, only the changed portions use new disk space.\n"
-            );
-            println!("{}", "Key differences:".bold());
-            println!(
-                "  • Reflinks: Different inodes, individual metadata, copy-on-write protection"
-            );
-            println!("  • Hard links: Shared inode, shared metadata, direct data sharing\n");
-            println!("{}", "Implications:".bold());
-            println!(
-                "  • With hard links, modifying any file affects all linked copies and the vault master"
-            );
-            println!("  • All hard-linked files share the same timestamps and permissions");
-            println!("  • Hard links save disk space but require careful file handling\n");
-            println!("{}", "Your options:".bold());
-            println!(
-                "  1. {} - Files will be skipped (safe default)",
-                "Do nothing".green()
-            );
-            println!(
-                "  2. {} - Enables deduplication with shared metadata",
-                "Add --allow-unsafe-hardlinks".yellow()
-            );
-            println!(
-                "  3. {} - Btrfs, XFS (Linux), APFS (macOS), ReFS (Windows)\n",
-                "Switch to a reflink-capable filesystem".cyan()
-            );
-            println!("{}", "━".repeat(80).yellow());
-            println!();
-            reflink_warning_shown = true;
+    options: &DedupeOptions,
+    trash: Option<&mut trash::Trash>,
+) -> Result<(Vec<BdError>, Summary)> {
+    let &DedupeOptions {
+        paranoid,
+        dry_run,
+        chain,
+        mode,
+        external_links,
+    } = options;
+    let info = run::RunInfo::new(&scan.root, mode_name(mode));
+    let run = if dry_run {
+        0
+    } else {
+        let run = state.begin_run(&info).map_err(BdError::Db)?;
+        println!("Run ID: {run}");
+        run
+    };
+    let linker = Linker {
+        paranoid,
+        dry_run,
+        chain,
+        mode,
+        trash: Mutex::new(trash),
+        recorder: run::Recorder::new(run, info),
+    };
+
+    let (db_tx, db_rx) = channel::bounded::<DbOp>(CHANNEL_CAPACITY);
+    let state_db_writer = state.clone();
+    let db_writer_handle =
+        std::thread::spawn(move || state_db_writer.batch_write_from_channel(db_rx));
+
+    let root = scan.root.clone();
+    let failures = Mutex::new(Vec::new());
+    let summary = stream_groups(scan, |groups| {
+        let mut skipped = groups
+            .iter_mut()
+            .map(|group| apply_external_links(group, &root, mode, external_links))
+            .collect::<Vec<_>>()
+            .into_iter();
+        // Skipped files can leave a group with nothing to link.
+        let mut outcomes = Vec::with_capacity(groups.len());
+        groups.retain(|group| {
+            let outcome = skipped.next().unwrap_or_default();
+            if group.paths.len() >= 2 {
+                outcomes.push(outcome);
+                true
+            } else {
+                outcome.print();
+                false
+            }
+        });
+        if !dry_run && mode != dedupe::DedupeMode::Delete {
+            record_original_metadata(groups, state)?;
+        }
+        groups
+            .par_iter()
+            .zip(outcomes)
+            .for_each(|(group, outcome)| {
+                let outcome = link_group(&linker, group, outcome);
+                outcome.print();
+                for op in outcome.db_ops {
+                    let _ = db_tx.send(op);
+                }
+                failures
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .extend(outcome.failures);
+            });
+        Ok(())
+    })?;
+    drop(db_tx);
+    db_writer_handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("state writer panicked"))?
+        .map_err(BdError::Db)?;
+
+    let failures = failures.into_inner().unwrap_or_else(|e| e.into_inner());
+    if !dry_run {
+        let mut info = linker.recorder.into_info();
+        info.finished = Some(run::now());
+        info.failures = failures.len() as u64;
+        state.put_run(run, &info).map_err(BdError::Db)?;
+    }
+    Ok((failures, summary))
+}
+
+/// Links one duplicate group: the master goes into the vault and every
+/// other file is linked to it, or with the symlink and delete modes
+/// resolved against the master in place. `out` already holds the lines for
+/// files that were left out of the group.
+fn link_group(linker: &Linker, group: &Group, mut out: GroupOutcome) -> GroupOutcome {
+    let Linker {
+        paranoid,
+        dry_run,
+        chain,
+        ..
+    } = *linker;
+    let Group {
+        hash,
+        paths,
+        stamps,
+        ..
+    } = group;
+    let master = &paths[0];
+
+    if linker.mode != dedupe::DedupeMode::Link {
+        resolve_in_place(linker, group, &mut out);
+        return out;
+    }
+
+    let mut master_lease = None;
+    let (vault_path, vault_outcome) = if dry_run {
+        let theoretical_path = match vault::shard_path(hash) {
+            Ok(path) => path,
+            Err(err) => {
+                out.failures.push(BdError::file(master, err));
+                return out;
+            }
+        };
+        let name = display_name(master);
+        out.output.push(format!(
+            "{} Would clone master (move if cloning is unsupported): {} -> {}",
+            "[DRY RUN]".yellow().dimmed(),
+            name,
+            theoretical_path.display()
+        ));
+        (theoretical_path, vault::VaultOutcome::AlreadyPresent)
+    } else {
+        match change::guard(master, stamps.get(master)) {
+            Ok(lease) => master_lease = Some(lease),
+            Err(failure) => {
+                out.failures.push(failure);
+                return out;
+            }
+        }
+        match vault::ensure_in_vault(hash, master) {
+            Ok(vaulted) => {
+                // Counted once the object exists, after files skipped for
+                // their outside links were taken out of the group.
+                out.db_ops
+                    .push(DbOp::SetCasRefcount(*hash, paths.len() as u64));
+                vaulted
+            }
+            Err(source) => {
+                out.failures.push(BdError::VaultIo {
+                    path: master.clone(),
+                    source,
+                });
+                return out;
+            }
         }
-        println!("{} {}", "[SKIPPED]".bold().red(), name);
     };
 
-    for (hash, paths) in groups {
-        if paths.len() < 2 {
-            continue;
+    let mut master_verified = false;
+    if paranoid && !dry_run && master.exists() {
+        match dedupe::compare_files(&vault_path, master) {
+            Ok(true) => master_verified = true,
+            Ok(false) => {
+                out.failures.push(BdError::HashMismatch {
+                    path: master.clone(),
+                });
+                return out;
+            }
+            Err(err) => {
+                out.failures.push(BdError::file(master, err));
+                return out;
+            }
         }
-        let master = &paths[0];
+    }
 
-        let vault_path = if dry_run {
-            let theoretical_path = vault::shard_path(hash)?;
-            let name = display_name(master);
-            println!(
-                "{} Would move master: {} -> {}",
-                "[DRY RUN]".yellow().dimmed(),
-                name,
-                theoretical_path.display()
-            );
-            theoretical_path
+    if paranoid && dry_run {
+        out.output.push(format!(
+            "{} Skipping paranoid verification (master not in vault)",
+            "[DRY RUN]".yellow().dimmed()
+        ));
+    }
+
+    let master_shares_vault = !dry_run
+        && match vault_outcome {
+            vault::VaultOutcome::Cloned => true,
+            vault::VaultOutcome::AlreadyPresent => vault::shares_data(&vault_path, master),
+            vault::VaultOutcome::Moved => false,
+        };
+    if master_shares_vault {
+        // The master already shares its extents with the vault copy and
+        // keeps its original inode (and its other names), so it is neither
+        // relinked nor recorded as replaced by the run.
+        if !is_temp_file(master) {
+            let verified = if master_verified {
+                format!(" {}", "[VERIFIED]".bold().blue())
+            } else {
+                String::new()
+            };
+            out.output.push(format!(
+                "{}{} {}",
+                "[MASTER  ]".bold().green(),
+                verified,
+                display_name(master)
+            ));
+        }
+    } else if !dry_run {
+        match link_into_vault(chain, &vault_path, master, paranoid, master_lease.as_ref()) {
+            Ok(Some((link_type, lost))) => {
+                out.failures.extend(metadata_lost(master, lost));
+                if link_type == dedupe::LinkType::HardLink {
+                    match std::fs::metadata(master) {
+                        Ok(meta) => out
+                            .db_ops
+                            .push(DbOp::MarkInodeVaulted(InodeId::from_metadata(&meta))),
+                        Err(err) => out.failures.push(BdError::file(master, err.into())),
+                    }
+                }
+                if !is_temp_file(master) {
+                    out.output
+                        .push(linked_line(link_type, master, paranoid && master_verified));
+                }
+                out.db_ops.push(linker.recorder.linked(
+                    master,
+                    hash,
+                    link_type,
+                    stamps.get(master),
+                    group.originals.get(master),
+                ));
+                out.db_ops.push(relinked(master, hash, stamps.get(master)));
+                relink_aliases(linker, group, master, link_type, &mut out);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                // A moved master must go back to its path before the group
+                // is skipped, whatever the reason the link failed.
+                if vault_outcome == vault::VaultOutcome::Moved
+                    && let Err(failure) = put_master_back(&vault_path, master)
+                {
+                    out.failures.push(failure);
+                }
+
+                match err.downcast::<BdError>() {
+                    Ok(BdError::ReflinkUnsupported { .. }) => {
+                        out.reflink_unsupported.push(display_name(master));
+                    }
+                    Ok(failure) => out.failures.push(failure),
+                    Err(err) => out.failures.push(BdError::file(master, err)),
+                }
+                return out;
+            }
+        }
+    } else {
+        let name = display_name(master);
+        out.output.push(format!(
+            "{} Would dedupe: {} -> {} ({})",
+            "[DRY RUN]".yellow().dimmed(),
+            name,
+            vault_path.display(),
+            chain.describe()
+        ));
+    }
+
+    // A cloned master keeps its inode, so a writer waiting on the lease
+    // only has to wait for the clone.
+    drop(master_lease);
+
+    for path in paths.iter().skip(1) {
+        let lease = if dry_run {
+            None
         } else {
-            vault::ensure_in_vault(hash, master)?
+            match change::guard(path, stamps.get(path)) {
+                Ok(lease) => Some(lease),
+                Err(failure) => {
+                    out.failures.push(failure);
+                    continue;
+                }
+            }
         };
 
-        let mut master_verified = false;
-        if paranoid && !dry_run && master.exists() {
-            match dedupe::compare_files(&vault_path, master) {
-                Ok(true) => master_verified = true,
+        let mut verified = false;
+        if paranoid && !dry_run {
+            match dedupe::compare_files(&vault_path, path) {
+                Ok(true) => verified = true,
                 Ok(false) => {
-                    eprintln!("HASH COLLISION OR BIT ROT DETECTED: {}", master.display());
+                    out.failures
+                        .push(BdError::HashMismatch { path: path.clone() });
                     continue;
                 }
                 Err(err) => {
-                    eprintln!("VERIFY FAILED (skipping): {}: {err}", master.display());
+                    out.failures.push(BdError::file(path, err));
                     continue;
                 }
             }
         }
 
-        if paranoid && dry_run {
-            println!(
-                "{} Skipping paranoid verification (master not in vault)",
-                "[DRY RUN]".yellow().dimmed()
-            );
-        }
-
-        let mut db_ops = Vec::new();
-
         if !dry_run {
-            match dedupe::replace_with_link(&vault_path, master, allow_unsafe_hardlinks) {
-                Ok(Some(link_type)) => {
+            match link_into_vault(chain, &vault_path, path, paranoid, lease.as_ref()) {
+                Ok(Some((link_type, lost))) => {
+                    out.failures.extend(metadata_lost(path, lost));
                     if link_type == dedupe::LinkType::HardLink {
-                        let inode = std::fs::metadata(master)?.ino();
-                        db_ops.push(DbOp::MarkInodeVaulted(inode));
-                    }
-                    if !is_temp_file(master) {
-                        let name = display_name(master);
-                        match link_type {
-                            dedupe::LinkType::Reflink => {
-                                if paranoid && master_verified {
-                                    println!(
-                                        "{} {} {}",
-                                        "[REFLINK ]".bold().green(),
-                                        "[VERIFIED]".bold().blue(),
-                                        name
-                                    );
-                                } else {
-                                    println!("{} {}", "[REFLINK ]".bold().green(), name);
-                                }
-                            }
-                            dedupe::LinkType::HardLink => {
-                                if paranoid && master_verified {
-                                    println!(
-                                        "{} {} {}",
-                                        "[HARDLINK]".bold().yellow(),
-                                        "[VERIFIED]".bold().blue(),
-                                        name
-                                    );
-                                } else {
-                                    println!("{} {}", "[HARDLINK]".bold().yellow(), name);
-                                }
-                            }
+                        match std::fs::metadata(path) {
+                            Ok(meta) => out
+                                .db_ops
+                                .push(DbOp::MarkInodeVaulted(InodeId::from_metadata(&meta))),
+                            Err(err) => out.failures.push(BdError::file(path, err.into())),
                         }
                     }
+                    if !is_temp_file(path) {
+                        out.output
+                            .push(linked_line(link_type, path, paranoid && verified));
+                    }
+                    out.db_ops.push(linker.recorder.linked(
+                        path,
+                        hash,
+                        link_type,
+                        stamps.get(path),
+                        group.originals.get(path),
+                    ));
+                    out.db_ops.push(relinked(path, hash, stamps.get(path)));
+                    relink_aliases(linker, group, path, link_type, &mut out);
                 }
                 Ok(None) => {}
-                Err(e) => {
-                    if e.to_string().contains("reflink not supported") {
-                        if let Err(restore_err) = std::fs::rename(&vault_path, master) {
-                            let copy_result = std::fs::copy(&vault_path, master)
-                                .and_then(|_| std::fs::remove_file(&vault_path));
-                            if let Err(copy_err) = copy_result {
-                                eprintln!(
-                                    "[ERROR] Failed to restore master from vault. File remains at {}. Rename error: {restore_err}. Copy/remove error: {copy_err}",
-                                    vault_path.display()
-                                );
-                            }
-                        }
-
-                        let name = display_name(master);
-                        warn_reflink_unsupported(&name);
-                        continue;
-                    } else {
-                        return Err(e);
+                Err(err) => match err.downcast::<BdError>() {
+                    Ok(BdError::ReflinkUnsupported { .. }) => {
+                        out.reflink_unsupported.push(display_name(path));
                     }
-                }
+                    Ok(failure) => out.failures.push(failure),
+                    Err(err) => out.failures.push(BdError::file(path, err)),
+                },
             }
         } else {
-            let name = display_name(master);
-            println!(
-                "{} Would dedupe: {} -> {} (reflink/hardlink)",
+            let name = display_name(path);
+            out.output.push(format!(
+                "{} Would dedupe: {} -> {} ({})",
                 "[DRY RUN]".yellow().dimmed(),
                 name,
-                vault_path.display()
-            );
+                vault_path.display(),
+                chain.describe()
+            ));
         }
+    }
 
-        for path in paths.iter().skip(1) {
-            let mut verified = false;
-            if paranoid && !dry_run {
-                match dedupe::compare_files(&vault_path, path) {
-                    Ok(true) => verified = true,
-                    Ok(false) => {
-                        eprintln!("HASH COLLISION OR BIT ROT DETECTED: {}", path.display());
-                        continue;
-                    }
-                    Err(err) => {
-                        eprintln!("VERIFY FAILED (skipping): {}: {err}", path.display());
-                        continue;
-                    }
+    if !dry_run {
+        out.db_ops
+            .push(DbOp::SetCasRefcount(*hash, paths.len() as u64));
+    } else {
+        let hex = crate::types::hash_to_hex(hash);
+        out.output.push(format!(
+            "{} Would update DB state for hash {}",
+            "[DRY RUN]".yellow().dimmed(),
+            hex
+        ));
+    }
+    out
+}
+
+fn print_reflink_warning(out: &mut impl Write) {
+    let _ = writeln!(out, "\n{}", "━".repeat(80).yellow());
+    let _ = writeln!(
+        out,
+        "{} Filesystem Does Not Support Copy-on-Write Reflinks",
+        "[WARNING]".bold().yellow()
+    );
+    let _ = writeln!(out, "{}", "━".repeat(80).yellow());
+    let _ = writeln!(
+        out,
+        "\nYour filesystem does not support CoW (Copy-on-Write) reflinks."
+    );
+    let _ = writeln!(
+        out,
+        "Reflinks allow files to share disk space while remaining independent copies."
+    );
+    let _ = writeln!(
+        out,
+        "When you modify a reflinked file, only the changed portions use new disk space.\n"
+    );
+    let _ = writeln!(out, "{}", "Key differences:".bold());
+    let _ = writeln!(
+        out,
+        "  • Reflinks: Different inodes, individual metadata, copy-on-write protection"
+    );
+    let _ = writeln!(
+        out,
+        "  • Hard links: Shared inode, shared metadata, direct data sharing\n"
+    );
+    let _ = writeln!(out, "{}", "Implications:".bold());
+    let _ = writeln!(
+        out,
+        "  • With hard links, modifying any file affects all linked copies and the vault master"
+    );
+    let _ = writeln!(
+        out,
+        "  • All hard-linked files share the same timestamps and permissions"
+    );
+    let _ = writeln!(
+        out,
+        "  • Hard links save disk space but require careful file handling\n"
+    );
+    let _ = writeln!(out, "{}", "Your options:".bold());
+    let _ = writeln!(
+        out,
+        "  1. {} - Files will be skipped (safe default)",
+        "Do nothing".green()
+    );
+    let _ = writeln!(
+        out,
+        "  2. {} - Enables deduplication with shared metadata",
+        "Add --allow-unsafe-hardlinks".yellow()
+    );
+    let _ = writeln!(
+        out,
+        "  3. {} - Btrfs, XFS (Linux), APFS (macOS), ReFS (Windows)\n",
+        "Switch to a reflink-capable filesystem".cyan()
+    );
+    let _ = writeln!(out, "{}", "━".repeat(80).yellow());
+    let _ = writeln!(out);
+}
+
+/// Applies the external link policy to a group of a dedupe under `root`.
+/// Files with names outside the root are dropped under
+/// [`dedupe::ExternalLinks::Skip`] and reported in the returned outcome.
+/// Other names under the root that are not relinked after their file join
+/// the group as files of their own: those of duplicates outside link mode,
+/// and all of them under [`dedupe::ExternalLinks::Break`].
+fn apply_external_links(
+    group: &mut Group,
+    root: &Path,
+    mode: dedupe::DedupeMode,
+    external_links: dedupe::ExternalLinks,
+) -> GroupOutcome {
+    let mut out = GroupOutcome::default();
+    let mut members = Vec::new();
+    for path in std::mem::take(&mut group.paths) {
+        let outside = group.linked_outside.contains(&path);
+        if outside && external_links == dedupe::ExternalLinks::Skip {
+            out.output.push(format!(
+                "{} {} (hard linked outside {})",
+                "[SKIPPED]".bold().red(),
+                display_name(&path),
+                root.display()
+            ));
+            group.aliases.remove(&path);
+            continue;
+        }
+        let separate = if mode == dedupe::DedupeMode::Link {
+            outside && external_links == dedupe::ExternalLinks::Break
+        } else {
+            !members.is_empty()
+        };
+        let aliases = if separate {
+            group.aliases.remove(&path)
+        } else {
+            None
+        };
+        members.push(path);
+        members.extend(aliases.into_iter().flatten());
+    }
+    group.paths = members;
+    out
+}
+
+/// Makes the other names under the root of `path`, which was just linked
+/// with `link_type`, hard links to it again so they keep sharing one inode.
+fn relink_aliases(
+    linker: &Linker,
+    group: &Group,
+    path: &Path,
+    link_type: dedupe::LinkType,
+    out: &mut GroupOutcome,
+) {
+    let hash = &group.hash;
+    for alias in group.aliases.get(path).into_iter().flatten() {
+        let stamp = group.stamps.get(alias);
+        match dedupe::relink_alias(path, alias, stamp) {
+            Ok(()) => {
+                out.output
+                    .push(linked_line(dedupe::LinkType::HardLink, alias, false));
+                out.db_ops.push(linker.recorder.alias_linked(
+                    alias,
+                    hash,
+                    link_type,
+                    stamp,
+                    group.originals.get(alias),
+                ));
+                out.db_ops.push(relinked(alias, hash, stamp));
+            }
+            Err(err) => match err.downcast::<BdError>() {
+                Ok(failure) => out.failures.push(failure),
+                Err(err) => out.failures.push(BdError::file(alias, err)),
+            },
+        }
+    }
+}
+
+fn mode_name(mode: dedupe::DedupeMode) -> &'static str {
+    match mode {
+        dedupe::DedupeMode::Link => "link",
+        dedupe::DedupeMode::Symlink => "symlink",
+        dedupe::DedupeMode::AbsoluteSymlink => "absolute-symlink",
+        dedupe::DedupeMode::Delete => "delete",
+    }
+}
+
+/// Stores the metadata of every file of `groups` before any of them is
+/// touched, and keeps it with each group for the run's own records.
+/// Hard-linked files share one inode, so this record is the only place a
+/// restore can find each file's own owner, mode and times.
+fn record_original_metadata(groups: &mut [Group], state: &state::State) -> Result<()> {
+    let mut ops = Vec::new();
+    for group in groups {
+        // Other names are relinked along with their file.
+        let names: Vec<PathBuf> = group
+            .paths
+            .iter()
+            .flat_map(|path| {
+                std::iter::once(path).chain(group.aliases.get(path).into_iter().flatten())
+            })
+            .cloned()
+            .collect();
+        for path in names {
+            // Files that cannot be read fail, and are reported, when linked.
+            if let Ok(saved) = dedupe::SavedMetadata::capture(&path) {
+                ops.push(DbOp::SaveOriginalMetadata(path.clone(), saved.clone()));
+                group.originals.insert(path, saved);
+            }
+        }
+    }
+    state.batch_write(ops).map_err(BdError::Db)?;
+    Ok(())
+}
+
+/// Moves a master that was moved into the vault back to its original path.
+fn put_master_back(vault_path: &Path, master: &Path) -> std::result::Result<(), BdError> {
+    if std::fs::rename(vault_path, master).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(vault_path, master)
+        .and_then(|_| std::fs::remove_file(vault_path))
+        .with_context(|| format!("restore master from vault; file remains at {vault_path:?}"))
+        .map_err(|source| BdError::VaultIo {
+            path: master.to_path_buf(),
+            source,
+        })
+}
+
+/// Links `target` to the vault copy through the strategy chain. The original
+/// is swapped back if paranoid verification fails or `lease` was broken.
+/// Returns the strategy used and the metadata the link could not keep.
+fn link_into_vault(
+    chain: &strategy::LinkChain,
+    vault_path: &Path,
+    target: &Path,
+    paranoid: bool,
+    lease: Option<&change::Lease>,
+) -> Result<Option<(dedupe::LinkType, Vec<String>)>> {
+    let vault_len = std::fs::metadata(vault_path)
+        .map_err(|err| BdError::VaultIo {
+            path: target.to_path_buf(),
+            source: err.into(),
+        })?
+        .len();
+    // A master that was moved into the vault has no file at its path yet.
+    match std::fs::metadata(target) {
+        Ok(meta) if meta.len() != vault_len => {
+            return Err(BdError::FileChanged {
+                path: target.to_path_buf(),
+                reason: "size differs from the vault copy".into(),
+            }
+            .into());
+        }
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(BdError::file(target, err.into()).into()),
+    }
+
+    let Some((strategy, swap)) = chain.link(vault_path, target)? else {
+        return Ok(None);
+    };
+    if paranoid {
+        let verified = strategy
+            .verify(vault_path, target)
+            .map_err(|err| BdError::file(target, err))?;
+        if !verified {
+            strategy
+                .undo(target, swap)
+                .map_err(|err| BdError::file(target, err))?;
+            return Err(BdError::HashMismatch {
+                path: target.to_path_buf(),
+            }
+            .into());
+        }
+    }
+    let lost = settle_swap(strategy, swap, lease, target)?;
+    Ok(Some((strategy.kind(), lost)))
+}
+
+/// Commits `swap` unless a writer opened `target` while it was in progress,
+/// in which case the original is put back for that writer. Returns the
+/// metadata the replacement could not be given.
+fn settle_swap(
+    strategy: &dyn LinkStrategy,
+    mut swap: dedupe::Swap,
+    lease: Option<&change::Lease>,
+    target: &Path,
+) -> std::result::Result<Vec<String>, BdError> {
+    if let Some(Err(failure)) = lease.map(|lease| lease.check_intact(target)) {
+        strategy
+            .undo(target, swap)
+            .map_err(|err| BdError::file(target, err))?;
+        return Err(failure);
+    }
+    let lost = std::mem::take(&mut swap.lost);
+    swap.commit().map_err(|err| BdError::file(target, err))?;
+    Ok(lost)
+}
+
+/// Reports the metadata `path` lost when it was replaced, if any.
+fn metadata_lost(path: &Path, lost: Vec<String>) -> Option<BdError> {
+    (!lost.is_empty()).then(|| BdError::MetadataLost {
+        path: path.to_path_buf(),
+        lost,
+    })
+}
+
+fn linked_line(link_type: dedupe::LinkType, path: &Path, verified: bool) -> String {
+    let label = match link_type {
+        dedupe::LinkType::Reflink => "[REFLINK ]".bold().green(),
+        dedupe::LinkType::HardLink => "[HARDLINK]".bold().yellow(),
+        dedupe::LinkType::Symlink => "[SYMLINK ]".bold().cyan(),
+        dedupe::LinkType::DedupeRange => "[DEDUPE  ]".bold().green(),
+        dedupe::LinkType::Copy => "[COPY    ]".bold().white(),
+        dedupe::LinkType::Deleted => "[DELETED ]".bold().red(),
+    };
+    let name = display_name(path);
+    if verified {
+        format!("{} {} {}", label, "[VERIFIED]".bold().blue(), name)
+    } else {
+        format!("{} {}", label, name)
+    }
+}
+
+/// Resolves a duplicate group without the vault: the master stays where it is
+/// and every other copy is replaced by a symlink to it or deleted.
+fn resolve_in_place(linker: &Linker, group: &Group, out: &mut GroupOutcome) {
+    let Linker {
+        paranoid,
+        dry_run,
+        mode,
+        ..
+    } = *linker;
+    let Group {
+        hash,
+        paths,
+        stamps,
+        ..
+    } = group;
+    let master = &paths[0];
+    let link_type = if mode == dedupe::DedupeMode::Delete {
+        dedupe::LinkType::Deleted
+    } else {
+        dedupe::LinkType::Symlink
+    };
+
+    // Every duplicate ends up pointing at or replaced by the master, so it
+    // must still hold the content that was hashed.
+    if !dry_run
+        && let Some(stamp) = stamps.get(master)
+        && let Err(failure) = change::check_unchanged(master, stamp)
+    {
+        out.failures.push(failure);
+        return;
+    }
+
+    for path in paths.iter().skip(1) {
+        let name = display_name(path);
+
+        if dry_run {
+            let trashing = linker
+                .trash
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_some();
+            let action = match mode {
+                dedupe::DedupeMode::Delete if trashing => "trash",
+                dedupe::DedupeMode::Delete => "delete",
+                _ => "symlink",
+            };
+            out.output.push(format!(
+                "{} Would {}: {} (master: {})",
+                "[DRY RUN]".yellow().dimmed(),
+                action,
+                name,
+                master.display()
+            ));
+            continue;
+        }
+
+        let lease = match change::guard(path, stamps.get(path)) {
+            Ok(lease) => lease,
+            Err(failure) => {
+                out.failures.push(failure);
+                continue;
+            }
+        };
+
+        let mut verified = false;
+        if paranoid {
+            match dedupe::compare_files(master, path) {
+                Ok(true) => verified = true,
+                Ok(false) => {
+                    out.failures
+                        .push(BdError::HashMismatch { path: path.clone() });
+                    continue;
+                }
+                Err(err) => {
+                    out.failures.push(BdError::file(path, err));
+                    continue;
                 }
             }
+        }
 
-            if !dry_run {
-                match dedupe::replace_with_link(&vault_path, path, allow_unsafe_hardlinks) {
-                    Ok(Some(link_type)) => {
-                        if link_type == dedupe::LinkType::HardLink {
-                            let inode = std::fs::metadata(path)?.ino();
-                            db_ops.push(DbOp::MarkInodeVaulted(inode));
-                        }
-                        if !is_temp_file(path) {
-                            let name = display_name(path);
-                            match link_type {
-                                dedupe::LinkType::Reflink => {
-                                    if paranoid && verified {
-                                        println!(
-                                            "{} {} {}",
-                                            "[REFLINK ]".bold().green(),
-                                            "[VERIFIED]".bold().blue(),
-                                            name
-                                        );
-                                    } else {
-                                        println!("{} {}", "[REFLINK ]".bold().green(), name);
-                                    }
-                                }
-                                dedupe::LinkType::HardLink => {
-                                    if paranoid && verified {
-                                        println!(
-                                            "{} {} {}",
-                                            "[HARDLINK]".bold().yellow(),
-                                            "[VERIFIED]".bold().blue(),
-                                            name
-                                        );
-                                    } else {
-                                        println!("{} {}", "[HARDLINK]".bold().yellow(), name);
-                                    }
-                                }
-                            }
-                        }
+        let result = match mode {
+            dedupe::DedupeMode::Symlink | dedupe::DedupeMode::AbsoluteSymlink => {
+                let symlink = strategy::SymlinkStrategy {
+                    relative: mode == dedupe::DedupeMode::Symlink,
+                };
+                symlink
+                    .link(master, path)
+                    .map_err(|err| BdError::file(path, err))
+                    .and_then(|swap| settle_swap(&symlink, swap, Some(&lease), path))
+                    .map(|_| Some(dedupe::LinkType::Symlink))
+            }
+            dedupe::DedupeMode::Delete => {
+                let mut trash = linker.trash.lock().unwrap_or_else(|e| e.into_inner());
+                dedupe::delete_duplicate(master, path, hash, trash.as_deref_mut())
+                    .map_err(|err| BdError::file(path, err))
+            }
+            dedupe::DedupeMode::Link => unreachable!("link mode goes through the vault"),
+        };
+
+        match result {
+            Ok(Some(_)) => {}
+            Ok(None) => continue,
+            Err(failure) => {
+                out.failures.push(failure);
+                continue;
+            }
+        };
+        out.db_ops.push(linker.recorder.linked(
+            path,
+            hash,
+            link_type,
+            stamps.get(path),
+            group.originals.get(path),
+        ));
+        if link_type == dedupe::LinkType::Deleted {
+            out.db_ops.push(DbOp::RemoveFileFromIndex(path.clone()));
+            // A deleted file cannot be swapped back, so a writer that showed
+            // up meanwhile can only be reported.
+            if let Err(failure) = lease.check_intact(path) {
+                out.failures.push(failure);
+                continue;
+            }
+        } else {
+            out.db_ops.push(relinked(path, hash, stamps.get(path)));
+        }
+        out.output.push(linked_line(link_type, path, verified));
+    }
+}
+
+/// Where a `scrub --limit` run stopped: the name of the last object checked.
+const SCRUB_CURSOR: &str = "scrub_cursor";
+
+/// Re-hashes vault objects and repairs damaged ones from intact copies. With
+/// `limit`, checks that many objects starting where the previous limited
+/// scrub stopped.
+fn scrub_pipeline(
+    state: &state::State,
+    limit: Option<u64>,
+    max_rate: Option<u64>,
+    dry_run: bool,
+) -> Result<Vec<BdError>> {
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner} {msg}")
+            .unwrap(),
+    );
+
+    let cursor = match limit {
+        Some(_) => state
+            .get_meta(SCRUB_CURSOR)
+            .map_err(BdError::Db)?
+            .map(|name| String::from_utf8_lossy(&name).into_owned()),
+        None => None,
+    };
+    let mut throttle = scrub::Throttle::new(max_rate.map(|rate| rate * 1_048_576));
+    let mut failures = Vec::new();
+    let mut checked = 0u64;
+    let mut bytes_checked = 0u64;
+    let mut repaired = 0u64;
+    let mut last = None;
+
+    let finished = scrub::for_each_object(cursor.as_deref(), |hash, path| {
+        if limit.is_some_and(|limit| checked >= limit) {
+            return Ok(false);
+        }
+        let hex = crate::types::hash_to_hex(&hash);
+        spinner.set_message(format!("Scrubbing {hex}..."));
+
+        match scrub::check_object(&hash, &path, state, &mut throttle, dry_run) {
+            Ok((scrub::Outcome::Intact, size)) => bytes_checked += size,
+            Ok((scrub::Outcome::Repaired { from, stale }, size)) => {
+                bytes_checked += size;
+                repaired += 1;
+                spinner.suspend(|| {
+                    if dry_run {
+                        println!(
+                            "{} Would repair {} from {}",
+                            "[DRY RUN]".yellow().dimmed(),
+                            hex,
+                            from.display()
+                        );
+                    } else {
+                        println!(
+                            "{} {} from {}",
+                            "[REPAIRED]".bold().green(),
+                            hex,
+                            from.display()
+                        );
                     }
-                    Ok(None) => {}
-                    Err(e) => {
-                        if e.to_string().contains("reflink not supported") {
-                            let name = display_name(path);
-                            warn_reflink_unsupported(&name);
-                            continue;
-                        } else {
-                            return Err(e);
-                        }
+                    for path in &stale {
+                        println!(
+                            "   hard link still holds the damaged data: {}",
+                            path.display()
+                        );
                     }
-                }
+                });
+            }
+            Ok((scrub::Outcome::Corrupt { affected }, size)) => {
+                bytes_checked += size;
+                spinner.suspend(|| {
+                    println!(
+                        "{} {} (no intact copy found)",
+                        "[CORRUPT]".bold().red(),
+                        hex
+                    );
+                    for path in &affected {
+                        println!("   affected: {}", path.display());
+                    }
+                });
+                failures.push(BdError::HashMismatch { path });
+            }
+            Err(source) => failures.push(BdError::VaultIo { path, source }),
+        }
+        checked += 1;
+        last = Some(hex);
+        Ok(true)
+    })?;
+    spinner.finish_and_clear();
+
+    if limit.is_some() && !dry_run {
+        // Once the end is reached the next limited scrub starts over.
+        let cursor = if finished { None } else { last.as_deref() };
+        state
+            .set_meta(SCRUB_CURSOR, cursor.map(str::as_bytes))
+            .map_err(BdError::Db)?;
+    }
+
+    println!(
+        "Scrub complete. Objects checked: {} ({:.2} MB), repaired: {}, unrepairable: {}",
+        checked,
+        bytes_checked as f64 / 1_048_576.0,
+        repaired,
+        failures
+            .iter()
+            .filter(|failure| matches!(failure, BdError::HashMismatch { .. }))
+            .count()
+    );
+    Ok(failures)
+}
+
+/// Regenerates the file index, refcounts and vaulted inodes from the vault
+/// and the files under `roots`. A database that cannot be opened is moved
+/// aside first; otherwise its run history and original metadata are kept.
+fn rebuild_state(roots: &[PathBuf], dry_run: bool) -> Result<Vec<BdError>> {
+    // Move legacy state first so the vault is skipped at its final location.
+    if !dry_run {
+        relocate_legacy_state()?;
+    }
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner} {msg}")
+            .unwrap(),
+    );
+
+    let mut index = rebuild::VaultIndex::load(|path| {
+        spinner.set_message(format!("Verifying vault object {}...", display_name(path)));
+    })?;
+    for path in &index.damaged {
+        spinner.suspend(|| {
+            println!(
+                "{} {} (run `bdstorage scrub` to repair it)",
+                "[CORRUPT]".bold().red(),
+                path.display()
+            )
+        });
+    }
+
+    // Without a vault there is no object for anything to be matched to.
+    let vault_root = match std::fs::canonicalize(vault::vault_root()?) {
+        Ok(root) => Some(root),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err).with_context(|| "resolve vault directory"),
+    };
+    let mut db_ops = Vec::new();
+    let mut refcounts: HashMap<Hash, u64> = HashMap::new();
+    let mut associated = 0u64;
+    for root in roots {
+        let canonical_root = std::fs::canonicalize(root)
+            .with_context(|| format!("resolve rebuild root {root:?}"))?;
+        for entry in jwalk::WalkDir::new(&canonical_root).into_iter() {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let path = entry.path();
+            let file_type = entry.file_type();
+            if !(file_type.is_file() || file_type.is_symlink()) || is_temp_file(&path) {
+                continue;
+            }
+            // The vault itself may live under a root.
+            if vault_root
+                .as_ref()
+                .is_some_and(|root| path.starts_with(root))
+            {
+                continue;
+            }
+            spinner.set_message(format!("Matching {}...", display_name(&path)));
+            // Symlinks left by the symlink strategies lead into the vault,
+            // those left by `--mode symlink` to a master elsewhere.
+            let (hash, association, meta) = if file_type.is_symlink() {
+                let found = vault_root
+                    .as_deref()
+                    .and_then(|root| index.associate_symlink(&path, root));
+                let Some(found) = found else {
+                    continue;
+                };
+                found
             } else {
-                let name = display_name(path);
+                let Ok(meta) = std::fs::metadata(&path) else {
+                    continue;
+                };
+                let Some((hash, association)) = index.associate(&path, &meta) else {
+                    continue;
+                };
+                (hash, association, meta)
+            };
+
+            spinner.suspend(|| {
                 println!(
-                    "{} Would dedupe: {} -> {} (reflink/hardlink)",
-                    "[DRY RUN]".yellow().dimmed(),
-                    name,
-                    vault_path.display()
-                );
+                    "{} {} ({})",
+                    "[ASSOCIATED]".bold().cyan(),
+                    path.display(),
+                    association.label()
+                )
+            });
+            associated += 1;
+            // Restoring a symlink to a master releases nothing, so it is
+            // not counted, as when dedupe made it.
+            if association != rebuild::Association::SymlinkToFile {
+                *refcounts.entry(hash).or_default() += 1;
             }
+            if association == rebuild::Association::HardLink {
+                db_ops.push(DbOp::MarkInodeVaulted(InodeId::from_metadata(&meta)));
+            }
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .unwrap_or_default()
+                .as_secs();
+            // Symlinks are recorded without an inode, as when they are made.
+            let inode = (!file_type.is_symlink()).then(|| InodeId::from_metadata(&meta));
+            db_ops.push(DbOp::UpsertFile(
+                path,
+                FileMetadata {
+                    size: meta.len(),
+                    modified,
+                    hash,
+                    inode,
+                },
+            ));
         }
-
-        if !dry_run {
-            db_ops.push(DbOp::SetCasRefcount(*hash, paths.len() as u64));
-            global_db_ops.extend(db_ops);
-            if global_db_ops.len() >= 1000 {
-                state.batch_write(std::mem::take(&mut global_db_ops))?;
+    }
+    spinner.finish_and_clear();
+
+    let unreferenced = index.object_count() - refcounts.len();
+    if dry_run {
+        println!(
+            "{} Would rebuild the state database with {} file(s) referencing {} vault object(s)",
+            "[DRY RUN]".yellow().dimmed(),
+            associated,
+            refcounts.len()
+        );
+    } else {
+        let state = match open_state(false) {
+            Ok(state) => state,
+            Err(err) => {
+                let aside = state::set_aside_db()?;
+                eprintln!(
+                    "{} The state database could not be opened ({err:#}); it was moved to {} and a new one created.",
+                    "[WARNING]".bold().yellow(),
+                    aside.display()
+                );
+                open_state(false)?
             }
+        };
+        db_ops.extend(
+            refcounts
+                .iter()
+                .map(|(hash, count)| DbOp::SetCasRefcount(*hash, *count)),
+        );
+        state.replace_index(db_ops).map_err(BdError::Db)?;
+    }
+
+    println!(
+        "Rebuild complete. Vault objects: {} verified, {} damaged, {} unreferenced. Files associated: {}",
+        index.object_count(),
+        index.damaged.len(),
+        unreferenced,
+        associated
+    );
+    Ok(index
+        .damaged
+        .into_iter()
+        .map(|path| BdError::HashMismatch { path })
+        .collect())
+}
+
+fn print_history(state: &state::State) -> Result<()> {
+    let runs = state.list_runs().map_err(BdError::Db)?;
+    if runs.is_empty() {
+        println!("No dedupe runs recorded.");
+        return Ok(());
+    }
+    println!(
+        "{:>5}  {:<19}  {:<16}  {:>7}  {:>10}  {:>8}  ROOT",
+        "RUN", "STARTED (UTC)", "MODE", "FILES", "SIZE (MB)", "FAILURES"
+    );
+    for (id, info) in runs {
+        let status = if info.undone.is_some() {
+            " (undone)".dimmed().to_string()
+        } else if info.finished.is_none() {
+            " (interrupted)".yellow().to_string()
         } else {
-            let hex = crate::types::hash_to_hex(hash);
+            String::new()
+        };
+        println!(
+            "{:>5}  {:<19}  {:<16}  {:>7}  {:>10.2}  {:>8}  {}{}",
+            id,
+            run::format_time(info.started),
+            info.mode,
+            info.files,
+            info.bytes as f64 / 1_048_576.0,
+            info.failures,
+            info.root,
+            status
+        );
+    }
+    Ok(())
+}
+
+/// Restores every file dedupe run `id` replaced, with the metadata recorded
+/// for it by that run, and marks the run as undone once all of them are.
+fn undo_run(id: u64, state: &state::State, dry_run: bool) -> Result<Vec<BdError>> {
+    let mut info = state
+        .get_run(id)
+        .map_err(BdError::Db)?
+        .with_context(|| format!("no dedupe run with ID {id}"))?;
+    if let Some(undone) = info.undone {
+        anyhow::bail!(
+            "run {id} was already undone at {} UTC",
+            run::format_time(undone)
+        );
+    }
+
+    let deleted = state
+        .run_files(id)
+        .map_err(BdError::Db)?
+        .into_iter()
+        .filter(|(_, file)| file.link_type == dedupe::LinkType::Deleted)
+        .count();
+    if deleted > 0 {
+        eprintln!(
+            "{} Run {id} deleted {deleted} file(s), which undo cannot bring back. Use `restore --trash <DIR>` if they were moved to a trash directory.",
+            "[WARNING]".bold().yellow()
+        );
+    }
+
+    // A file restored without some of its extended attributes still counts
+    // as restored; anything else left the run partly in place.
+    let failures = restore_pipeline(&RestoreSelection::Run(id, None), state, dry_run)?;
+    let complete = failures
+        .iter()
+        .all(|failure| matches!(failure, BdError::MetadataLost { .. }));
+    if !dry_run && complete {
+        info.undone = Some(run::now());
+        state.put_run(id, &info).map_err(BdError::Db)?;
+    }
+    Ok(failures)
+}
+
+fn restore_trash(dir: &Path, dry_run: bool) -> Result<()> {
+    let restored = trash::restore_all(dir, dry_run)?;
+    let mut bytes_restored = 0;
+    for entry in &restored {
+        let name = display_name(&entry.original);
+        if dry_run {
             println!(
-                "{} Would update DB state for hash {}",
+                "{} Would restore from trash: {}",
                 "[DRY RUN]".yellow().dimmed(),
-                hex
+                name
             );
+        } else {
+            println!("{} {}", "[UNTRASHED]".bold().cyan(), name);
         }
+        bytes_restored += entry.size;
     }
-
-    if !dry_run && !global_db_ops.is_empty() {
-        state.batch_write(global_db_ops)?;
-    }
+    println!(
+        "Trash restore complete. Files restored: {} ({:.2} MB)",
+        restored.len(),
+        bytes_restored as f64 / 1_048_576.0
+    );
     Ok(())
 }
 
@@ -504,13 +1757,25 @@ fn is_temp_file(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
-fn file_modified(path: &Path) -> Result<u64> {
-    let metadata = std::fs::metadata(path).with_context(|| "read metadata")?;
-    let modified = metadata.modified().with_context(|| "read modified time")?;
-    let duration = modified
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default();
-    Ok(duration.as_secs())
+/// The index record of `path` after it was linked, which usually gives it
+/// another inode. Size and modification time are those of that inode, so a
+/// later scan can tell it from an unrelated file that reuses the inode; they
+/// are those it was hashed with if it is no longer a regular file.
+fn relinked(path: &Path, hash: &Hash, stamp: Option<&FileStamp>) -> DbOp {
+    let linked = std::fs::symlink_metadata(path)
+        .ok()
+        .filter(|meta| meta.is_file())
+        .map(|meta| FileStamp::from_metadata(&meta));
+    let stamp = linked.as_ref().or(stamp);
+    DbOp::UpsertFile(
+        path.to_path_buf(),
+        FileMetadata {
+            size: stamp.map_or(0, |stamp| stamp.size),
+            modified: stamp.map_or(0, |stamp| (stamp.mtime_ns / 1_000_000_000) as u64),
+            hash: *hash,
+            inode: linked.map(|stamp| stamp.inode()),
+        },
+    )
 }
 
 fn progress(label: &str, total: u64) -> ProgressBar {
@@ -524,12 +1789,320 @@ fn progress(label: &str, total: u64) -> ProgressBar {
     bar
 }
 
-fn print_summary(mode: &str, groups: &HashMap<Hash, Vec<PathBuf>>) {
-    let duplicates = groups.values().filter(|g| g.len() > 1).count();
-    println!("{mode} complete. duplicate groups: {duplicates}");
+/// Prints what the scan had to skip and enforces `--strict`.
+fn finish_scan(skips: &skip::SkipLog, strict: bool) -> Result<()> {
+    skips.flush()?;
+    let totals = skips.totals();
+    if totals.is_empty() {
+        return Ok(());
+    }
+
+    let skipped = skips.skipped();
+    eprintln!(
+        "{} {} entries could not be scanned:",
+        "[WARNING]".bold().yellow(),
+        skipped
+    );
+    for (reason, totals) in &totals {
+        eprintln!(
+            "  {}: {} ({:.2} MB)",
+            reason.label(),
+            totals.entries,
+            totals.bytes as f64 / 1_048_576.0
+        );
+    }
+
+    if strict {
+        return Err(BdError::ScanIncomplete { skipped }.into());
+    }
+    Ok(())
+}
+
+fn print_summary(mode: &str, summary: &Summary) {
+    println!(
+        "{mode} complete. duplicate groups: {} ({:.2} MB reclaimable)",
+        summary.groups,
+        summary.reclaimable as f64 / 1_048_576.0
+    );
+}
+
+/// The bytes linking `group` frees: the data of all its files but one. Hard
+/// linked names of a file are counted once, and files kept alive by names
+/// outside the root free nothing.
+fn reclaimable_bytes(group: &Group) -> u64 {
+    let inode = |stamp: &FileStamp| (stamp.dev, stamp.ino);
+    let outside: HashSet<_> = group
+        .linked_outside
+        .iter()
+        .filter_map(|path| group.stamps.get(path).map(inode))
+        .collect();
+    let mut files = HashMap::new();
+    for stamp in group.paths.iter().filter_map(|path| group.stamps.get(path)) {
+        if !outside.contains(&inode(stamp)) {
+            files.insert(inode(stamp), stamp.size);
+        }
+    }
+    files.values().skip(1).sum()
 }
 
-fn restore_pipeline(path: &Path, state: &state::State, dry_run: bool) -> Result<()> {
+/// A deduplicated file found by the restore walk. `recorded` is the metadata
+/// a dedupe run recorded for it, which takes precedence over the per-path
+/// record when the file is restored as part of that run.
+enum RestoreTarget {
+    /// A symlink left by a symlink-mode dedupe.
+    Symlink {
+        path: PathBuf,
+        file_meta: FileMetadata,
+        recorded: Option<dedupe::SavedMetadata>,
+    },
+    /// A file sharing its data with the vault.
+    File {
+        path: PathBuf,
+        /// Where the records of the file are kept: `path`, or the path it
+        /// had before it was moved or renamed.
+        indexed_as: PathBuf,
+        inode: InodeId,
+        size: u64,
+        hash: Option<Hash>,
+        inode_vaulted: bool,
+        recorded: Option<dedupe::SavedMetadata>,
+    },
+}
+
+impl RestoreTarget {
+    fn path(&self) -> &Path {
+        match self {
+            RestoreTarget::Symlink { path, .. } | RestoreTarget::File { path, .. } => path,
+        }
+    }
+
+    /// Disk space the private copy will take. Holes are preserved, so this is
+    /// the allocated size rather than the length.
+    fn space_needed(&self) -> u64 {
+        std::fs::metadata(self.path())
+            .map(|meta| meta.blocks() * 512)
+            .unwrap_or(0)
+    }
+}
+
+/// Which deduplicated files a restore brings back.
+enum RestoreSelection {
+    /// Everything under a directory, or a single file.
+    Path(PathBuf),
+    /// Files under a directory whose path relative to it matches a pattern.
+    Glob(PathBuf, glob::Pattern),
+    /// Indexed files with this content, optionally only those under a path.
+    Hash(Hash, Option<PathBuf>),
+    /// Files linked by a dedupe run, optionally only those under a path.
+    Run(u64, Option<PathBuf>),
+}
+
+impl RestoreSelection {
+    fn from_args(
+        path: Option<PathBuf>,
+        glob: Option<String>,
+        hash: Option<String>,
+        run: Option<u64>,
+    ) -> Result<Self> {
+        // A path that no longer exists can still scope a hash or run.
+        let path = path.map(|path| state::canonical_path(&path).unwrap_or(path));
+        if let Some(hex) = hash {
+            let hash = crate::types::hash_from_hex(&hex)
+                .with_context(|| format!("invalid hash {hex:?}"))?;
+            return Ok(RestoreSelection::Hash(hash, path));
+        }
+        if let Some(run) = run {
+            return Ok(RestoreSelection::Run(run, path));
+        }
+        let path = path.context("a path is required")?;
+        match glob {
+            Some(pattern) => {
+                let pattern = glob::Pattern::new(&pattern)
+                    .with_context(|| format!("invalid glob {pattern:?}"))?;
+                Ok(RestoreSelection::Glob(path, pattern))
+            }
+            None => Ok(RestoreSelection::Path(path)),
+        }
+    }
+}
+
+/// The files `selection` covers that can be restored, and failures for the
+/// recorded ones that cannot.
+fn find_restore_targets(
+    selection: &RestoreSelection,
+    state: &state::State,
+) -> Result<(Vec<RestoreTarget>, Vec<BdError>)> {
+    let (root, pattern) = match selection {
+        RestoreSelection::Path(root) => (root, None),
+        RestoreSelection::Glob(root, pattern) => (root, Some(pattern)),
+        RestoreSelection::Hash(hash, scope) => {
+            let paths = state.files_with_hash(hash).map_err(BdError::Db)?;
+            let paths = paths.into_iter().map(|path| (path, None)).collect();
+            return restore_targets_in(paths, scope.as_deref(), false, state);
+        }
+        RestoreSelection::Run(run, scope) => {
+            if state.get_run(*run).map_err(BdError::Db)?.is_none() {
+                anyhow::bail!("no dedupe run with ID {run}");
+            }
+            // Deleted files are not on disk to restore; their path may hold a
+            // new, unrelated file by now.
+            let paths = state
+                .run_files(*run)
+                .map_err(BdError::Db)?
+                .into_iter()
+                .filter(|(_, file)| file.link_type != dedupe::LinkType::Deleted)
+                .map(|(path, file)| (path, file.original))
+                .collect();
+            return restore_targets_in(paths, scope.as_deref(), true, state);
+        }
+    };
+
+    if !std::fs::symlink_metadata(root).is_ok_and(|meta| meta.is_dir()) {
+        return restore_targets_in(vec![(root.clone(), None)], None, false, state);
+    }
+
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    let mut targets = Vec::new();
+    for entry in jwalk::WalkDir::new(root).into_iter() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let file_path = entry.path();
+        if let Some(pattern) = pattern {
+            let relative = file_path.strip_prefix(root).unwrap_or(&file_path);
+            if !pattern.matches_path_with(relative, options) {
+                continue;
+            }
+        }
+        if let Some(target) = restore_target(file_path, entry.file_type(), state) {
+            targets.push(target);
+        }
+    }
+
+    Ok((targets, Vec::new()))
+}
+
+/// Restore targets among `paths`, keeping only those under `scope` if given.
+/// When the paths are the files a run linked (`linked_by_run`), the ones that
+/// are gone, or that still have a record but no longer share data with the
+/// vault, are returned as failures; files without a record were already
+/// restored. Other paths are skipped silently when there is nothing to do.
+fn restore_targets_in(
+    paths: Vec<(PathBuf, Option<dedupe::SavedMetadata>)>,
+    scope: Option<&Path>,
+    linked_by_run: bool,
+    state: &state::State,
+) -> Result<(Vec<RestoreTarget>, Vec<BdError>)> {
+    let mut targets = Vec::new();
+    let mut failures = Vec::new();
+    for (path, original) in paths {
+        if !scope.is_none_or(|scope| path.starts_with(scope)) {
+            continue;
+        }
+        let file_type = match std::fs::symlink_metadata(&path) {
+            Ok(meta) => meta.file_type(),
+            Err(_) if !linked_by_run => continue,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                failures.push(BdError::FileChanged {
+                    path,
+                    reason: "no longer exists".into(),
+                });
+                continue;
+            }
+            Err(err) => {
+                failures.push(BdError::file(&path, err.into()));
+                continue;
+            }
+        };
+        match restore_target(path.clone(), file_type, state) {
+            Some(mut target) => {
+                match &mut target {
+                    RestoreTarget::Symlink { recorded, .. }
+                    | RestoreTarget::File { recorded, .. } => *recorded = original,
+                }
+                targets.push(target);
+            }
+            None => {
+                if linked_by_run && state.lookup_file(&path).map_err(BdError::Db)?.is_some() {
+                    failures.push(BdError::FileChanged {
+                        path,
+                        reason: "no longer shares its data with the vault".into(),
+                    });
+                }
+            }
+        }
+    }
+    Ok((targets, failures))
+}
+
+/// Decides whether `path` shares data with the vault and can be restored.
+fn restore_target(
+    path: PathBuf,
+    file_type: std::fs::FileType,
+    state: &state::State,
+) -> Option<RestoreTarget> {
+    if is_temp_file(&path) {
+        return None;
+    }
+    if file_type.is_symlink() {
+        let file_meta = state.lookup_file(&path).ok()??;
+        return Some(RestoreTarget::Symlink {
+            path,
+            file_meta,
+            recorded: None,
+        });
+    }
+    if !file_type.is_file() {
+        return None;
+    }
+
+    let metadata = std::fs::metadata(&path).ok()?;
+    let inode = InodeId::from_metadata(&metadata);
+    let size = metadata.len();
+
+    let inode_vaulted = state.is_inode_vaulted(inode).unwrap_or(false);
+    let stamp = FileStamp::from_metadata(&metadata);
+    let record = state.find_record(&path, &stamp).ok().flatten();
+    let hash = record.as_ref().map(|(_, file_meta)| file_meta.hash);
+    let indexed_as = record.map_or_else(|| path.clone(), |(indexed_as, _)| indexed_as);
+    let in_vault = hash
+        .and_then(|hash| vault::shard_path(&hash).ok())
+        .is_some_and(|vault_path| vault_path.exists());
+
+    (inode_vaulted || in_vault).then_some(RestoreTarget::File {
+        path,
+        indexed_as,
+        inode,
+        size,
+        hash,
+        inode_vaulted,
+        recorded: None,
+    })
+}
+
+/// Fails with [`BdError::NoSpace`] if a private copy of `path` would not fit.
+fn check_space(path: &Path, needed: u64) -> std::result::Result<(), BdError> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let available = staging::available_space(dir).map_err(|err| BdError::file(path, err))?;
+    if needed > available {
+        return Err(BdError::NoSpace {
+            path: path.to_path_buf(),
+            needed,
+            available,
+        });
+    }
+    Ok(())
+}
+
+fn restore_pipeline(
+    selection: &RestoreSelection,
+    state: &state::State,
+    dry_run: bool,
+) -> Result<Vec<BdError>> {
     let multi = MultiProgress::new();
     let restore_spinner = multi.add(ProgressBar::new_spinner());
     restore_spinner.set_style(
@@ -542,98 +2115,160 @@ fn restore_pipeline(path: &Path, state: &state::State, dry_run: bool) -> Result<
     let mut restored_count = 0;
     let mut bytes_restored = 0;
     let mut global_restore_ops = Vec::new();
-
-    for entry in jwalk::WalkDir::new(path).into_iter() {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(_) => continue,
-        };
-        if !entry.file_type().is_file() {
+    let mut refcounts = HashMap::new();
+    let (targets, mut failures) = find_restore_targets(selection, state)?;
+
+    // Rehydrating undoes the sharing, so every restored file needs its own
+    // space again, on the filesystem it lives on. Files are still checked one
+    // by one below; this only warns up front that some will not fit.
+    let mut needed: HashMap<u64, (PathBuf, u64)> = HashMap::new();
+    for target in &targets {
+        let Some(dir) = target.path().parent() else {
             continue;
+        };
+        if let Ok(meta) = std::fs::metadata(dir) {
+            needed
+                .entry(meta.dev())
+                .or_insert_with(|| (dir.to_path_buf(), 0))
+                .1 += target.space_needed();
         }
-        let file_path = entry.path();
-        if is_temp_file(&file_path) {
-            continue;
+    }
+    for (dir, needed) in needed.values() {
+        if let Ok(available) = staging::available_space(dir)
+            && *needed > available
+        {
+            restore_spinner.suspend(|| {
+                eprintln!(
+                    "{} Restoring needs {:.2} MB on the filesystem of {} but only {:.2} MB are free; files that do not fit will be skipped.",
+                    "[WARNING]".bold().yellow(),
+                    *needed as f64 / 1_048_576.0,
+                    dir.display(),
+                    available as f64 / 1_048_576.0
+                )
+            });
         }
+    }
 
-        let metadata = match std::fs::metadata(&file_path) {
-            Ok(m) => m,
-            Err(_) => continue,
-        };
+    for target in targets {
+        let file_path = target.path().to_path_buf();
+        let name = display_name(&file_path);
+        restore_spinner.set_message(format!("Restoring {name}..."));
 
-        let inode = metadata.ino();
-        let size = metadata.len();
+        if !dry_run && let Err(failure) = check_space(&file_path, target.space_needed()) {
+            failures.push(failure);
+            continue;
+        }
 
-        let mut needs_restore = false;
-        let mut target_hash: Option<Hash> = None;
+        match target {
+            RestoreTarget::Symlink {
+                file_meta,
+                recorded,
+                ..
+            } => {
+                if dry_run {
+                    println!("{} Would restore: {}", "[DRY RUN]".yellow().dimmed(), name);
+                    restored_count += 1;
+                    bytes_restored += file_meta.size;
+                    continue;
+                }
 
-        if state.is_inode_vaulted(inode).unwrap_or(false) {
-            needs_restore = true;
-            if let Ok(Some(file_meta)) = state.get_file_metadata(&file_path) {
-                target_hash = Some(file_meta.hash);
+                let original = match recorded {
+                    Some(recorded) => Some(recorded),
+                    None => state
+                        .get_original_metadata(&file_path)
+                        .map_err(BdError::Db)?,
+                };
+                let restored = symlink_points_into_vault(&file_path).and_then(|into_vault| {
+                    let lost = dedupe::restore_symlink(&file_path, original.as_ref())?;
+                    Ok((into_vault, lost))
+                });
+                match restored {
+                    Ok((points_into_vault, lost)) => {
+                        println!("{} {}", "[RESTORED]".bold().cyan(), name);
+                        failures.extend(metadata_lost(&file_path, lost));
+                        global_restore_ops.push(DbOp::RemoveFileFromIndex(file_path.clone()));
+                        global_restore_ops.push(DbOp::RemoveOriginalMetadata(file_path.clone()));
+                        if points_into_vault {
+                            release_vault_ref(
+                                state,
+                                &mut refcounts,
+                                &file_meta.hash,
+                                &mut global_restore_ops,
+                            );
+                        }
+                        restored_count += 1;
+                        bytes_restored += file_meta.size;
+                    }
+                    Err(err) => failures.push(BdError::file(&file_path, err)),
+                }
             }
-        } else if let Ok(Some(file_meta)) = state.get_file_metadata(&file_path)
-            && let Ok(vault_path) = vault::shard_path(&file_meta.hash)
-            && vault_path.exists()
-        {
-            needs_restore = true;
-            target_hash = Some(file_meta.hash);
-        }
-
-        if needs_restore {
-            let name = display_name(&file_path);
-            restore_spinner.set_message(format!("Restoring {name}..."));
+            RestoreTarget::File {
+                indexed_as,
+                inode,
+                size,
+                hash,
+                inode_vaulted,
+                recorded,
+                ..
+            } => {
+                if dry_run {
+                    println!("{} Would restore: {}", "[DRY RUN]".yellow().dimmed(), name);
+                    if let Some(hash) = hash {
+                        println!(
+                            "{}   -> Would decrement refcount for {}",
+                            "[DRY RUN]".yellow().dimmed(),
+                            crate::types::hash_to_hex(&hash)
+                        );
+                    }
+                    restored_count += 1;
+                    bytes_restored += size;
+                    continue;
+                }
 
-            if dry_run {
-                println!("{} Would restore: {}", "[DRY RUN]".yellow().dimmed(), name);
-                if let Some(hash) = target_hash {
-                    println!(
-                        "{}   -> Would decrement refcount for {}",
-                        "[DRY RUN]".yellow().dimmed(),
-                        crate::types::hash_to_hex(&hash)
-                    );
+                // Hard-linked files share one inode, so only the metadata
+                // recorded before dedupe tells them apart. Other files kept
+                // their own.
+                let original = match recorded {
+                    _ if !inode_vaulted => None,
+                    Some(recorded) => Some(recorded),
+                    None => state
+                        .get_original_metadata(&indexed_as)
+                        .map_err(BdError::Db)?,
+                };
+                match dedupe::restore_file(&file_path, original.as_ref()) {
+                    Ok(lost) => failures.extend(metadata_lost(&file_path, lost)),
+                    Err(err) => {
+                        failures.push(BdError::file(&file_path, err));
+                        continue;
+                    }
                 }
-                restored_count += 1;
-                bytes_restored += size;
-            } else if dedupe::restore_file(&file_path).is_ok() {
                 println!("{} {}", "[RESTORED]".bold().cyan(), name);
 
                 let mut restore_ops = vec![
                     DbOp::UnmarkInodeVaulted(inode),
-                    DbOp::RemoveFileFromIndex(file_path.clone()),
+                    DbOp::RemoveFileFromIndex(indexed_as.clone()),
+                    DbOp::RemoveOriginalMetadata(indexed_as),
                 ];
 
-                if let Some(hash) = target_hash
-                    && let Ok(mut current_refcount) = state.get_cas_refcount(&hash)
-                    && current_refcount > 0
-                {
-                    current_refcount -= 1;
-                    if current_refcount == 0 {
-                        let _ = vault::remove_from_vault(&hash);
-                        restore_ops.push(DbOp::RemoveCasRefcount(hash));
-                        println!(
-                            "{}    -> Vault copy pruned (refcount 0)",
-                            "[GC]".bold().magenta()
-                        );
-                    } else {
-                        restore_ops.push(DbOp::SetCasRefcount(hash, current_refcount));
-                    }
+                if let Some(hash) = hash {
+                    release_vault_ref(state, &mut refcounts, &hash, &mut restore_ops);
                 }
                 global_restore_ops.extend(restore_ops);
-                if global_restore_ops.len() >= 1000 {
-                    let _ = state.batch_write(std::mem::take(&mut global_restore_ops));
-                }
 
                 restored_count += 1;
                 bytes_restored += size;
-            } else {
-                eprintln!("{} Failed to restore {name}", "[ERROR]".bold().red());
             }
         }
+
+        if global_restore_ops.len() >= 1000 {
+            state
+                .batch_write(std::mem::take(&mut global_restore_ops))
+                .map_err(BdError::Db)?;
+        }
     }
 
     if !global_restore_ops.is_empty() {
-        let _ = state.batch_write(global_restore_ops);
+        state.batch_write(global_restore_ops).map_err(BdError::Db)?;
     }
 
     restore_spinner.finish_and_clear();
@@ -642,5 +2277,42 @@ fn restore_pipeline(path: &Path, state: &state::State, dry_run: bool) -> Result<
         restored_count,
         bytes_restored as f64 / 1_048_576.0
     );
-    Ok(())
+    Ok(failures)
+}
+
+/// Drops one reference to a vault entry, pruning it once nothing uses it.
+/// `refcounts` tracks counts whose updates are still sitting in the batch.
+fn release_vault_ref(
+    state: &state::State,
+    refcounts: &mut HashMap<Hash, u64>,
+    hash: &Hash,
+    ops: &mut Vec<DbOp>,
+) {
+    let current_refcount = match refcounts.get(hash) {
+        Some(count) => Ok(*count),
+        None => state.get_cas_refcount(hash),
+    };
+    if let Ok(mut current_refcount) = current_refcount
+        && current_refcount > 0
+    {
+        current_refcount -= 1;
+        refcounts.insert(*hash, current_refcount);
+        if current_refcount == 0 {
+            let _ = vault::remove_from_vault(hash);
+            ops.push(DbOp::RemoveCasRefcount(*hash));
+            println!(
+                "{}    -> Vault copy pruned (refcount 0)",
+                "[GC]".bold().magenta()
+            );
+        } else {
+            ops.push(DbOp::SetCasRefcount(*hash, current_refcount));
+        }
+    }
+}
+
+fn symlink_points_into_vault(path: &Path) -> Result<bool> {
+    let resolved = std::fs::canonicalize(path).with_context(|| "resolve symlink")?;
+    let vault_root = vault::vault_root()?;
+    let vault_root = std::fs::canonicalize(&vault_root).unwrap_or(vault_root);
+    Ok(resolved.starts_with(vault_root))
 }
@@ -0,0 +1,486 @@
+use crate::dedupe::SavedMetadata;
+use crate::run::{RunFile, RunInfo};
+use crate::state;
+use crate::types::{FileMetadata, InodeId};
+use anyhow::{Context, Result};
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Version of the table layouts and value encodings this build writes.
+pub const SCHEMA_VERSION: u32 = 7;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// A value stored as bincode behind a one-byte format tag, so its layout can
+/// change without breaking databases written by older versions.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Tag written with new values. Bump it whenever the layout changes and
+    /// decode the previous layouts in [`Versioned::upgrade`].
+    const FORMAT: u8;
+
+    /// Decodes a value written with an older `format`.
+    fn upgrade(format: u8, _bytes: &[u8]) -> Result<Self> {
+        anyhow::bail!("unknown value format {format}")
+    }
+}
+
+impl Versioned for FileMetadata {
+    const FORMAT: u8 = 2;
+
+    fn upgrade(format: u8, bytes: &[u8]) -> Result<Self> {
+        match format {
+            // Before the inode was recorded.
+            1 => {
+                let (size, modified, hash) = bincode::deserialize(bytes)?;
+                Ok(Self {
+                    size,
+                    modified,
+                    hash,
+                    inode: None,
+                })
+            }
+            _ => anyhow::bail!("unknown file metadata format {format}"),
+        }
+    }
+}
+
+impl Versioned for SavedMetadata {
+    const FORMAT: u8 = 1;
+}
+
+impl Versioned for RunInfo {
+    const FORMAT: u8 = 1;
+}
+
+impl Versioned for RunFile {
+    const FORMAT: u8 = 2;
+
+    fn upgrade(format: u8, bytes: &[u8]) -> Result<Self> {
+        match format {
+            // Before the device was recorded with the inode. An inode number
+            // alone is ambiguous across filesystems, so it is dropped.
+            1 => {
+                let (hash, _prior_inode, link_type, original): (_, u64, _, _) =
+                    bincode::deserialize(bytes)?;
+                Ok(Self {
+                    hash,
+                    prior_inode: None,
+                    link_type,
+                    original,
+                })
+            }
+            _ => anyhow::bail!("unknown run file format {format}"),
+        }
+    }
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Result<Vec<u8>> {
+    let mut bytes = vec![T::FORMAT];
+    bincode::serialize_into(&mut bytes, value)?;
+    Ok(bytes)
+}
+
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Result<T> {
+    match bytes.split_first() {
+        Some((&format, rest)) if format == T::FORMAT => Ok(bincode::deserialize(rest)?),
+        Some((&format, rest)) => T::upgrade(format, rest),
+        None => anyhow::bail!("empty value"),
+    }
+}
+
+/// A schema upgrade applied while opening the database.
+pub struct Upgrade {
+    pub from: u32,
+    pub to: u32,
+    /// Copy of the database as it was before, unless only a throwaway copy
+    /// was upgraded.
+    pub backup: Option<PathBuf>,
+    /// The directory of the throwaway copy, removed when the upgrade is
+    /// dropped along with the database opened from it.
+    _scratch: Option<tempfile::TempDir>,
+}
+
+struct Migration {
+    to: u32,
+    apply: fn(&WriteTransaction) -> Result<()>,
+}
+
+/// Every migration in order. Each one upgrades from the version before
+/// `to`, and all that are due run in the transaction that records the new
+/// version, so a failure leaves the database as it was.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 2,
+        apply: tag_values,
+    },
+    Migration {
+        to: 3,
+        apply: raw_path_keys,
+    },
+    Migration {
+        to: 4,
+        apply: canonical_path_keys,
+    },
+    Migration {
+        to: 5,
+        apply: device_inode_keys,
+    },
+    Migration {
+        to: 6,
+        apply: inode_paths,
+    },
+    Migration {
+        to: 7,
+        apply: run_paths,
+    },
+];
+
+/// Version 1 stored bincode values without a format tag.
+fn tag_values(txn: &WriteTransaction) -> Result<()> {
+    for name in ["file_index", "original_metadata", "runs", "run_files"] {
+        let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new(name);
+        let mut table = txn.open_table(definition)?;
+        let mut entries = Vec::new();
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            entries.push((key.value().to_vec(), value.value().to_vec()));
+        }
+        for (key, value) in entries {
+            let mut tagged = Vec::with_capacity(value.len() + 1);
+            tagged.push(1);
+            tagged.extend_from_slice(&value);
+            table.insert(key.as_slice(), tagged.as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 2 keyed paths by their lossy UTF-8 form, so names that are not
+/// UTF-8 lost bytes and could collide. Such keys are matched back to the one
+/// file on disk they can have come from; keys that match no file, or more
+/// than one, cannot be recovered and are dropped.
+fn raw_path_keys(txn: &WriteTransaction) -> Result<()> {
+    // Tables keyed by a path, after a prefix of this many bytes.
+    for (name, prefix) in [
+        ("file_index", 0),
+        ("original_metadata", 0),
+        ("run_files", 8),
+    ] {
+        let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new(name);
+        let mut table = txn.open_table(definition)?;
+        let mut lossy = Vec::new();
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            let key = key.value();
+            let path = String::from_utf8_lossy(key.get(prefix..).unwrap_or_default());
+            if path.contains(char::REPLACEMENT_CHARACTER) {
+                lossy.push((key.to_vec(), value.value().to_vec()));
+            }
+        }
+        for (key, value) in lossy {
+            table.remove(key.as_slice())?;
+            let path = String::from_utf8_lossy(&key[prefix..]).into_owned();
+            if let Some(path) = resolve_lossy(&path) {
+                let mut raw = key[..prefix].to_vec();
+                raw.extend_from_slice(state::path_key(&path));
+                table.insert(raw.as_slice(), value.as_slice())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Version 3 keyed paths as the scan root was spelled. Absolute keys are
+/// canonicalized. Relative keys depend on the directory dedupe ran in, so
+/// they are only carried over when a file at that path from the current
+/// directory still has the recorded size and modification time.
+fn canonical_path_keys(txn: &WriteTransaction) -> Result<()> {
+    let mut renamed: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new("file_index");
+    let mut table = txn.open_table(definition)?;
+    let mut entries = Vec::new();
+    for entry in table.iter()? {
+        let (key, value) = entry?;
+        entries.push((key.value().to_vec(), value.value().to_vec()));
+    }
+    for (key, value) in entries {
+        let path = state::path_from_key(&key);
+        let canonical = if path.is_absolute() {
+            state::canonical_path(&path).ok()
+        } else {
+            // Layout of a version 1 `FileMetadata` behind its format tag.
+            let recorded: Option<(u64, u64)> = value
+                .get(1..)
+                .and_then(|bytes| bincode::deserialize(bytes).ok());
+            std::fs::metadata(&path)
+                .ok()
+                .filter(|meta| recorded == Some((meta.len(), mtime_secs(meta))))
+                .and_then(|_| state::canonical_path(&path).ok())
+        };
+        let new_key = match canonical {
+            Some(canonical) => state::path_key(&canonical).to_vec(),
+            None if path.is_absolute() => continue,
+            None => {
+                table.remove(key.as_slice())?;
+                continue;
+            }
+        };
+        if new_key != key {
+            table.remove(key.as_slice())?;
+            table.insert(new_key.as_slice(), value.as_slice())?;
+            renamed.insert(key, new_key);
+        }
+    }
+    drop(table);
+
+    for (name, prefix) in [("original_metadata", 0), ("run_files", 8)] {
+        let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new(name);
+        let mut table = txn.open_table(definition)?;
+        let mut moves = Vec::new();
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            let key = key.value();
+            let Some(new_path) = key.get(prefix..).and_then(|path| renamed.get(path)) else {
+                continue;
+            };
+            let mut new_key = key[..prefix].to_vec();
+            new_key.extend_from_slice(new_path);
+            moves.push((key.to_vec(), new_key, value.value().to_vec()));
+        }
+        for (key, new_key, value) in moves {
+            table.remove(key.as_slice())?;
+            table.insert(new_key.as_slice(), value.as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 4 keyed vaulted inodes by inode number alone. A vaulted inode is
+/// hard linked to a vault object, so the device is taken from the object
+/// with that inode; entries matching no object are stale and dropped.
+fn device_inode_keys(txn: &WriteTransaction) -> Result<()> {
+    let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new("vaulted_inodes");
+    let mut table = txn.open_table(definition)?;
+    let mut old = Vec::new();
+    for entry in table.iter()? {
+        let (key, _) = entry?;
+        if let Ok(bytes) = <[u8; 8]>::try_from(key.value()) {
+            old.push(u64::from_le_bytes(bytes));
+        }
+    }
+    if old.is_empty() {
+        return Ok(());
+    }
+    for ino in &old {
+        table.remove(ino.to_le_bytes().as_slice())?;
+    }
+
+    let mut vaulted: HashSet<u64> = old.into_iter().collect();
+    crate::scrub::for_each_object(None, |_, path| {
+        if let Ok(meta) = std::fs::metadata(&path)
+            && vaulted.remove(&meta.ino())
+        {
+            let key = state::inode_key(InodeId::from_metadata(&meta));
+            table.insert(key.as_slice(), [1u8].as_slice())?;
+        }
+        Ok(!vaulted.is_empty())
+    })?;
+    Ok(())
+}
+
+/// Version 6 records the inode of every indexed file and indexes paths by
+/// inode so records can follow moved files.
+fn inode_paths(txn: &WriteTransaction) -> Result<()> {
+    let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new("file_index");
+    let mut table = txn.open_table(definition)?;
+    let mut entries = Vec::new();
+    for entry in table.iter()? {
+        let (key, value) = entry?;
+        entries.push((key.value().to_vec(), value.value().to_vec()));
+    }
+    let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new("inode_paths");
+    let mut inode_paths = txn.open_table(definition)?;
+    for (key, value) in entries {
+        let mut metadata: FileMetadata = decode(&value)?;
+        metadata.inode = std::fs::symlink_metadata(state::path_from_key(&key))
+            .ok()
+            .filter(|meta| meta.is_file())
+            .map(|meta| InodeId::from_metadata(&meta));
+        if let Some(inode) = metadata.inode {
+            let index_key = state::inode_path_key(inode, &state::path_from_key(&key));
+            inode_paths.insert(index_key.as_slice(), [].as_slice())?;
+        }
+        table.insert(key.as_slice(), encode(&metadata)?.as_slice())?;
+    }
+    Ok(())
+}
+
+/// Version 7 indexes run records by path, so moving a file re-keys only its
+/// own records.
+fn run_paths(txn: &WriteTransaction) -> Result<()> {
+    let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new("run_files");
+    let table = txn.open_table(definition)?;
+    let definition: TableDefinition<&[u8], &[u8]> = TableDefinition::new("run_paths");
+    let mut run_paths = txn.open_table(definition)?;
+    for entry in table.iter()? {
+        let (key, _) = entry?;
+        let Some((id, path)) = key.value().split_first_chunk::<8>() else {
+            continue;
+        };
+        let index_key = state::run_path_key(&state::path_from_key(path), u64::from_be_bytes(*id));
+        run_paths.insert(index_key.as_slice(), [].as_slice())?;
+    }
+    Ok(())
+}
+
+fn mtime_secs(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Finds the path whose lossy form is `lossy`, if exactly one exists.
+fn resolve_lossy(lossy: &str) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in Path::new(lossy).components() {
+        let name = component.as_os_str();
+        if !name.to_string_lossy().contains(char::REPLACEMENT_CHARACTER) {
+            resolved.push(name);
+            continue;
+        }
+        let dir = if resolved.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            resolved.as_path()
+        };
+        let mut matches = std::fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .filter(|candidate| candidate.to_string_lossy() == name.to_string_lossy());
+        let found = matches.next()?;
+        if matches.next().is_some() {
+            return None;
+        }
+        resolved.push(found);
+    }
+    Some(resolved)
+}
+
+/// Brings the database at `path` up to [`SCHEMA_VERSION`]. The database is
+/// backed up next to itself first. With `readonly`, it is left alone and a
+/// temporary copy is upgraded and returned instead.
+pub fn upgrade(db: Database, path: &Path, readonly: bool) -> Result<(Database, Option<Upgrade>)> {
+    let from = match stored_version(&db)? {
+        // A new database starts out at the current version.
+        None => {
+            set_version(&db)?;
+            return Ok((db, None));
+        }
+        Some(version) if version == SCHEMA_VERSION => return Ok((db, None)),
+        Some(version) if version > SCHEMA_VERSION => anyhow::bail!(
+            "state database {path:?} has schema version {version}, but this bdstorage only supports up to {SCHEMA_VERSION}; upgrade bdstorage"
+        ),
+        Some(version) => version,
+    };
+    drop(db);
+
+    let (target, backup, scratch) = if readonly {
+        let scratch = tempfile::Builder::new()
+            .prefix("bdstorage-dry-")
+            .tempdir()
+            .with_context(|| "create directory for dry run")?;
+        let copy = scratch.path().join("state.redb");
+        std::fs::copy(path, &copy).with_context(|| "copy state database for dry run")?;
+        (copy, None, Some(scratch))
+    } else {
+        let backup = path.with_extension(format!("redb.backup-v{from}-{}", crate::run::now()));
+        std::fs::copy(path, &backup)
+            .with_context(|| format!("back up state database to {backup:?}"))?;
+        (path.to_path_buf(), Some(backup), None)
+    };
+
+    let db = Database::create(&target).with_context(|| "reopen redb database for migration")?;
+    let txn = db
+        .begin_write()
+        .with_context(|| "begin migration transaction")?;
+    for migration in MIGRATIONS.iter().filter(|migration| migration.to > from) {
+        (migration.apply)(&txn)
+            .with_context(|| format!("migrate state database to version {}", migration.to))?;
+    }
+    write_version(&txn)?;
+    txn.commit().with_context(|| "commit migration")?;
+    Ok((
+        db,
+        Some(Upgrade {
+            from,
+            to: SCHEMA_VERSION,
+            backup,
+            _scratch: scratch,
+        }),
+    ))
+}
+
+/// The recorded schema version. Databases from before versioning have
+/// tables but no record and count as version 1; `None` means a new one.
+fn stored_version(db: &Database) -> Result<Option<u32>> {
+    let txn = db.begin_read().with_context(|| "begin read transaction")?;
+    match txn.open_table(state::META) {
+        Ok(table) => {
+            if let Some(access) = table.get(SCHEMA_VERSION_KEY)? {
+                let bytes: [u8; 4] = access
+                    .value()
+                    .try_into()
+                    .with_context(|| "malformed schema version")?;
+                return Ok(Some(u32::from_le_bytes(bytes)));
+            }
+        }
+        Err(redb::TableError::TableDoesNotExist(_)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let has_tables = txn.list_tables()?.next().is_some();
+    Ok(has_tables.then_some(1))
+}
+
+fn set_version(db: &Database) -> Result<()> {
+    let txn = db
+        .begin_write()
+        .with_context(|| "begin write transaction")?;
+    write_version(&txn)?;
+    txn.commit().with_context(|| "commit schema version")?;
+    Ok(())
+}
+
+fn write_version(txn: &WriteTransaction) -> Result<()> {
+    let mut table = txn.open_table(state::META)?;
+    table.insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION.to_le_bytes().as_slice())?;
+    Ok(())
+}
+
+/// Moves state from the `~/.imprint` directory used before the rename to
+/// `~/.bdstorage`. The directory is renamed as a whole, so vault objects
+/// keep their inodes, and a symlink is left in its place so absolute
+/// symlinks into the old vault still resolve. Returns the old location if
+/// it was moved.
+pub fn relocate_legacy() -> Result<Option<PathBuf>> {
+    let legacy = state::legacy_data_dir()?;
+    if !state::is_real_dir(&legacy) {
+        return Ok(None);
+    }
+    let current = state::home_dir()?.join(state::DATA_DIR);
+    if std::fs::symlink_metadata(&current).is_ok() {
+        anyhow::bail!(
+            "both {legacy:?} and {current:?} exist; keep the one in use as {current:?} and remove the other"
+        );
+    }
+    std::fs::rename(&legacy, &current)
+        .with_context(|| format!("move {legacy:?} to {current:?}"))?;
+    std::os::unix::fs::symlink(state::DATA_DIR, &legacy)
+        .with_context(|| format!("link {legacy:?} to {current:?}"))?;
+    Ok(Some(legacy))
+}
@@ -0,0 +1,148 @@
+use crate::device::{self, Extent};
+use crate::hasher;
+use crate::scrub;
+use crate::types::Hash;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// How a file was found to hold the data of a vault object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Association {
+    /// The file is a hard link to the object.
+    HardLink,
+    /// The file is a reflink of the object: same length, same extents.
+    SharedExtents,
+    /// The file has its own copy of the data.
+    SameHash,
+    /// The file is a symlink to the object.
+    Symlink,
+    /// The file is a symlink to a file that holds the object's data, as
+    /// `--mode symlink` leaves them. It holds no reference to the object.
+    SymlinkToFile,
+}
+
+impl Association {
+    pub fn label(self) -> &'static str {
+        match self {
+            Association::HardLink => "hard link",
+            Association::SharedExtents => "shared extents",
+            Association::SameHash => "same hash",
+            Association::Symlink => "symlink",
+            Association::SymlinkToFile => "symlink to a matched file",
+        }
+    }
+}
+
+struct VaultObject {
+    path: PathBuf,
+    /// Filled in the first time a file of the same size is compared.
+    extents: Option<Option<Vec<Extent>>>,
+}
+
+/// The verified objects of the vault, indexed the ways files are matched
+/// against them.
+pub struct VaultIndex {
+    objects: HashMap<Hash, VaultObject>,
+    by_size: HashMap<u64, Vec<Hash>>,
+    by_inode: HashMap<(u64, u64), Hash>,
+    /// Symlink targets outside the vault already matched, by inode.
+    targets: HashMap<(u64, u64), Option<Hash>>,
+    /// Objects whose content no longer matches their name.
+    pub damaged: Vec<PathBuf>,
+}
+
+impl VaultIndex {
+    /// Hashes every vault object. Objects that fail to verify are listed in
+    /// `damaged` and left out.
+    pub fn load(mut progress: impl FnMut(&Path)) -> Result<Self> {
+        let mut index = Self {
+            objects: HashMap::new(),
+            by_size: HashMap::new(),
+            by_inode: HashMap::new(),
+            targets: HashMap::new(),
+            damaged: Vec::new(),
+        };
+        scrub::for_each_object(None, |hash, path| {
+            progress(&path);
+            let verified = hasher::full_hash(&path).is_ok_and(|actual| actual == hash);
+            match std::fs::metadata(&path) {
+                Ok(meta) if verified => {
+                    index.by_size.entry(meta.len()).or_default().push(hash);
+                    index.by_inode.insert((meta.dev(), meta.ino()), hash);
+                    index.objects.insert(
+                        hash,
+                        VaultObject {
+                            path,
+                            extents: None,
+                        },
+                    );
+                }
+                _ => index.damaged.push(path),
+            }
+            Ok(true)
+        })?;
+        Ok(index)
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Finds the object the symlink `path` leads to, with the metadata of
+    /// its target: an object under `vault_root` (which must be canonical), or
+    /// a file elsewhere that is matched to an object.
+    pub fn associate_symlink(
+        &mut self,
+        path: &Path,
+        vault_root: &Path,
+    ) -> Option<(Hash, Association, Metadata)> {
+        let target = std::fs::canonicalize(path).ok()?;
+        let meta = std::fs::metadata(&target).ok()?;
+        let inode = (meta.dev(), meta.ino());
+        if target.starts_with(vault_root) {
+            let hash = self.by_inode.get(&inode)?;
+            return Some((*hash, Association::Symlink, meta));
+        }
+        if !meta.is_file() {
+            return None;
+        }
+        let hash = match self.targets.get(&inode) {
+            Some(hash) => *hash,
+            None => {
+                let hash = self.associate(&target, &meta).map(|(hash, _)| hash);
+                self.targets.insert(inode, hash);
+                hash
+            }
+        }?;
+        Some((hash, Association::SymlinkToFile, meta))
+    }
+
+    /// Finds the object `path` holds the data of, trying the cheap checks
+    /// before hashing the file.
+    pub fn associate(&mut self, path: &Path, meta: &Metadata) -> Option<(Hash, Association)> {
+        if let Some(hash) = self.by_inode.get(&(meta.dev(), meta.ino())) {
+            return Some((*hash, Association::HardLink));
+        }
+        let candidates = self.by_size.get(&meta.len())?.clone();
+
+        if let Some(file_extents) = device::extents(path) {
+            for hash in &candidates {
+                let object = self.objects.get_mut(hash)?;
+                let object_extents = object
+                    .extents
+                    .get_or_insert_with(|| device::extents(&object.path));
+                if object_extents.as_ref() == Some(&file_extents) {
+                    return Some((*hash, Association::SharedExtents));
+                }
+            }
+        }
+
+        let hash = hasher::full_hash(path).ok()?;
+        candidates
+            .contains(&hash)
+            .then_some((hash, Association::SameHash))
+    }
+}
@@ -0,0 +1,152 @@
+use crate::change::FileStamp;
+use crate::dedupe::{LinkType, SavedMetadata};
+use crate::state::DbOp;
+use crate::types::{Hash, InodeId};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A dedupe run as listed by `history`. Created when the run starts and
+/// updated with its totals when it finishes and again if it is undone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunInfo {
+    /// Times in seconds since the Unix epoch.
+    pub started: u64,
+    pub finished: Option<u64>,
+    pub undone: Option<u64>,
+    pub root: String,
+    pub mode: String,
+    /// Files the run replaced, and their combined size.
+    pub files: u64,
+    pub bytes: u64,
+    pub failures: u64,
+}
+
+impl RunInfo {
+    pub fn new(root: &Path, mode: &str) -> Self {
+        Self {
+            started: now(),
+            finished: None,
+            undone: None,
+            root: std::fs::canonicalize(root)
+                .unwrap_or_else(|_| root.to_path_buf())
+                .to_string_lossy()
+                .into_owned(),
+            mode: mode.to_string(),
+            files: 0,
+            bytes: 0,
+            failures: 0,
+        }
+    }
+}
+
+/// One file a run replaced, with what is needed to put it back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunFile {
+    pub hash: Hash,
+    /// The inode the file had when it was hashed. Unknown when the run had no
+    /// stamp for it, and for runs recorded before the device was.
+    pub prior_inode: Option<InodeId>,
+    pub link_type: LinkType,
+    pub original: Option<SavedMetadata>,
+}
+
+/// Builds the records of a run as its files are linked and keeps its totals.
+/// Shared by the threads linking groups of the run.
+pub struct Recorder {
+    pub id: u64,
+    info: RunInfo,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Recorder {
+    pub fn new(id: u64, info: RunInfo) -> Self {
+        Self {
+            id,
+            info,
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// The run with the totals of every file recorded.
+    pub fn into_info(self) -> RunInfo {
+        let mut info = self.info;
+        info.files += self.files.into_inner();
+        info.bytes += self.bytes.into_inner();
+        info
+    }
+
+    /// Records that the run replaced `path`, which `stamp` describes as it
+    /// was when hashed and `original` as it was before the run.
+    pub fn linked(
+        &self,
+        path: &Path,
+        hash: &Hash,
+        link_type: LinkType,
+        stamp: Option<&FileStamp>,
+        original: Option<&SavedMetadata>,
+    ) -> DbOp {
+        self.bytes
+            .fetch_add(stamp.map_or(0, |stamp| stamp.size), Ordering::Relaxed);
+        self.alias_linked(path, hash, link_type, stamp, original)
+    }
+
+    /// Records that the run relinked `path`, another name of a file it
+    /// replaced. Its bytes were counted with that file.
+    pub fn alias_linked(
+        &self,
+        path: &Path,
+        hash: &Hash,
+        link_type: LinkType,
+        stamp: Option<&FileStamp>,
+        original: Option<&SavedMetadata>,
+    ) -> DbOp {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        DbOp::RecordRunFile(
+            self.id,
+            path.to_path_buf(),
+            RunFile {
+                hash: *hash,
+                prior_inode: stamp.map(FileStamp::inode),
+                link_type,
+                original: original.cloned(),
+            },
+        )
+    }
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Formats seconds since the epoch as a UTC date and time.
+pub fn format_time(secs: u64) -> String {
+    // Civil date from a day count, after Howard Hinnant's `civil_from_days`.
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        time / 3_600,
+        time / 60 % 60,
+        time % 60
+    )
+}
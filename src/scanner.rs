@@ -1,3 +1,5 @@
+use crate::change::FileStamp;
+use crate::skip::SkipLog;
 use anyhow::Result;
 use crossbeam::channel::Sender;
 use jwalk::WalkDir;
@@ -37,12 +39,29 @@ pub fn group_by_size(root: &Path) -> Result<HashMap<u64, Vec<PathBuf>>> {
     Ok(groups)
 }
 
-pub fn stream_scan(root: &Path, tx: Sender<PathBuf>) -> Result<()> {
+/// A regular file found by [`stream_scan`], stat'ed once for every later
+/// stage of the scan.
+#[derive(Debug, Clone)]
+pub struct ScannedFile {
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+}
+
+pub fn stream_scan(root: &Path, tx: Sender<ScannedFile>, skips: &SkipLog) -> Result<()> {
     for entry in WalkDir::new(root).into_iter() {
-        let entry = match entry {
+        let mut entry = match entry {
             Ok(entry) => entry,
-            Err(_) => continue,
+            Err(err) => {
+                let path = err.path().unwrap_or(root).to_path_buf();
+                skips.record(&path, None, &walk_error(err));
+                continue;
+            }
         };
+        // jwalk reports unreadable directories on the entry itself rather than
+        // through the iterator.
+        if let Some(err) = entry.read_children_error.take() {
+            skips.record(&entry.path(), None, &walk_error(err));
+        }
         if !entry.file_type().is_file() {
             continue;
         }
@@ -53,12 +72,23 @@ pub fn stream_scan(root: &Path, tx: Sender<PathBuf>) -> Result<()> {
         {
             continue;
         }
-        let _metadata = match entry.metadata() {
-            Ok(metadata) => metadata,
-            Err(_) => continue,
-        };
-        let path = entry.path().to_path_buf();
-        let _ = tx.send(path);
+        let path = entry.path();
+        match FileStamp::of(&path) {
+            Ok(stamp) => {
+                let _ = tx.send(ScannedFile { path, stamp });
+            }
+            Err(err) => skips.record(&path, None, &err.into()),
+        }
     }
     Ok(())
 }
+
+/// Converts a walk error into one whose cause chain exposes the underlying
+/// `io::ErrorKind`, so it can be categorized.
+fn walk_error(err: jwalk::Error) -> anyhow::Error {
+    let kind = err
+        .io_error()
+        .map(|io| io.kind())
+        .unwrap_or(std::io::ErrorKind::Other);
+    anyhow::Error::new(std::io::Error::new(kind, err.to_string()))
+}
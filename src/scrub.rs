@@ -0,0 +1,202 @@
+use crate::hasher;
+use crate::staging::StagedFile;
+use crate::state::State;
+use crate::types::{Hash, hash_from_hex};
+use crate::vault;
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const BUFFER_SIZE: usize = 128 * 1024;
+
+/// What scrubbing found for one vault object.
+pub enum Outcome {
+    Intact,
+    /// The object was rewritten from an intact copy at `from`. `stale` lists
+    /// hard links to the damaged inode, which the repair leaves untouched.
+    Repaired {
+        from: PathBuf,
+        stale: Vec<PathBuf>,
+    },
+    /// No intact copy was found. `affected` lists every indexed path with
+    /// this hash.
+    Corrupt {
+        affected: Vec<PathBuf>,
+    },
+}
+
+/// Caps the read rate of a scrub so it can run alongside other I/O.
+pub struct Throttle {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Accounts for `len` bytes read and sleeps if reads are ahead of the cap.
+    fn consume(&mut self, len: usize) {
+        let Some(rate) = self.bytes_per_sec.filter(|rate| *rate > 0) else {
+            return;
+        };
+        self.bytes += len as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+    }
+}
+
+/// Calls `visit` with every vault object after `cursor`, the name of an
+/// earlier object, in name order until it returns `false`. Returns whether
+/// the end of the vault was reached.
+pub fn for_each_object(
+    cursor: Option<&str>,
+    mut visit: impl FnMut(Hash, PathBuf) -> Result<bool>,
+) -> Result<bool> {
+    let root = vault::vault_root()?;
+    for (shard_a, dir_a) in sorted_entries(&root)? {
+        if cursor.is_some_and(|cursor| shard_a.as_str() < &cursor[..2]) {
+            continue;
+        }
+        for (shard_b, dir_b) in sorted_entries(&dir_a)? {
+            let prefix = format!("{shard_a}{shard_b}");
+            if cursor.is_some_and(|cursor| prefix.as_str() < &cursor[..4]) {
+                continue;
+            }
+            for (name, path) in sorted_entries(&dir_b)? {
+                if cursor.is_some_and(|cursor| name.as_str() <= cursor) {
+                    continue;
+                }
+                // Leftover temp files are not objects.
+                let Some(hash) = hash_from_hex(&name) else {
+                    continue;
+                };
+                if !visit(hash, path)? {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("read vault directory {dir:?}")),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("read vault directory {dir:?}"))?;
+        if let Some(name) = entry.file_name().to_str() {
+            names.push((name.to_string(), entry.path()));
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Re-hashes the object at `path` and, unless `dry_run`, repairs it when it
+/// no longer matches `hash`. Returns the outcome and the bytes read.
+pub fn check_object(
+    hash: &Hash,
+    path: &Path,
+    state: &State,
+    throttle: &mut Throttle,
+    dry_run: bool,
+) -> Result<(Outcome, u64)> {
+    let (actual, size) = hash_file(path, throttle)?;
+    if actual == *hash {
+        return Ok((Outcome::Intact, size));
+    }
+
+    let object = std::fs::metadata(path).with_context(|| "read vault object metadata")?;
+    let mut stale = Vec::new();
+    let mut source = None;
+    let affected = state.files_with_hash(hash)?;
+    for candidate in &affected {
+        let Ok(meta) = std::fs::symlink_metadata(candidate) else {
+            continue;
+        };
+        if !meta.is_file() {
+            continue;
+        }
+        if (meta.dev(), meta.ino()) == (object.dev(), object.ino()) {
+            stale.push(candidate.clone());
+            continue;
+        }
+        // A file that is itself hard linked may have been edited through its
+        // other name, so only single-link copies are trusted.
+        if source.is_none()
+            && meta.nlink() == 1
+            && hash_file(candidate, throttle)
+                .is_ok_and(|(candidate_hash, _)| candidate_hash == *hash)
+        {
+            source = Some(candidate.clone());
+        }
+    }
+
+    let Some(source) = source else {
+        return Ok((Outcome::Corrupt { affected }, size));
+    };
+    if !dry_run {
+        repair(hash, path, &source, throttle)?;
+    }
+    Ok((
+        Outcome::Repaired {
+            from: source,
+            stale,
+        },
+        size,
+    ))
+}
+
+/// Replaces the object at `path` with a copy of `source`, keeping the damaged
+/// object unless the copy verifies.
+fn repair(hash: &Hash, path: &Path, source: &Path, throttle: &mut Throttle) -> Result<()> {
+    let mut staged = StagedFile::create(path)?;
+    staged
+        .copy_from(source)
+        .with_context(|| "copy intact file into vault temp")?;
+    staged
+        .file()
+        .sync_all()
+        .with_context(|| "sync repaired vault object")?;
+    let swap = staged.swap_into(path)?;
+    let (repaired, _) = hash_file(path, throttle)?;
+    if repaired != *hash {
+        swap.rollback()?;
+        anyhow::bail!("repaired object does not match its hash; {source:?} changed during repair");
+    }
+    swap.commit()
+}
+
+fn hash_file(path: &Path, throttle: &mut Throttle) -> Result<(Hash, u64)> {
+    let mut file = hasher::open_noatime(path).with_context(|| format!("open file {path:?}"))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut size = 0;
+
+    loop {
+        let read = file.read(&mut buffer).with_context(|| "read file")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+        throttle.consume(read);
+    }
+
+    Ok((hasher.finalize().into(), size))
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Why an entry was left out of a scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    PermissionDenied,
+    Vanished,
+    Io,
+}
+
+impl SkipReason {
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        let io_kind = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .map(|io| io.kind());
+        match io_kind {
+            Some(std::io::ErrorKind::PermissionDenied) => SkipReason::PermissionDenied,
+            Some(std::io::ErrorKind::NotFound) => SkipReason::Vanished,
+            _ => SkipReason::Io,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SkipReason::PermissionDenied => "permission denied",
+            SkipReason::Vanished => "vanished during scan",
+            SkipReason::Io => "I/O error",
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct SkipTotals {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+struct Inner {
+    totals: BTreeMap<SkipReason, SkipTotals>,
+    log: Option<BufWriter<File>>,
+}
+
+/// Shared, thread-safe record of everything the scan could not cover.
+#[derive(Clone)]
+pub struct SkipLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SkipLog {
+    /// Creates a log, optionally mirroring every skipped entry to `log_path`
+    /// as `reason<TAB>path<TAB>error` lines.
+    pub fn new(log_path: Option<&Path>) -> Result<Self> {
+        let log = log_path
+            .map(|path| {
+                File::create(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("create errors log {:?}", path))
+            })
+            .transpose()?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                totals: BTreeMap::new(),
+                log,
+            })),
+        })
+    }
+
+    /// Records a skipped entry. `bytes` is the entry's size when known.
+    pub fn record(&self, path: &Path, bytes: Option<u64>, err: &anyhow::Error) {
+        let reason = SkipReason::from_error(err);
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+
+        let totals = inner.totals.entry(reason).or_default();
+        totals.entries += 1;
+        totals.bytes += bytes.unwrap_or(0);
+
+        if let Some(log) = inner.log.as_mut() {
+            let _ = writeln!(log, "{}\t{}\t{:#}", reason.label(), path.display(), err);
+        }
+    }
+
+    pub fn totals(&self) -> Vec<(SkipReason, SkipTotals)> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.totals.iter().map(|(r, t)| (*r, *t)).collect()
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.totals().iter().map(|(_, t)| t.entries).sum()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(log) = inner.log.as_mut() {
+            log.flush().with_context(|| "flush errors log")?;
+        }
+        Ok(())
+    }
+}
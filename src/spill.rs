@@ -0,0 +1,619 @@
+use crate::change::FileStamp;
+use crate::scanner::ScannedFile;
+use crate::state::inode_key;
+use crate::types::{Hash, InodeId};
+use anyhow::{Context, Result};
+use redb::{
+    Database, Durability, MultimapTableDefinition, ReadableMultimapTable, ReadableTable,
+    TableDefinition, WriteTransaction,
+};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The first file of each size, or an empty value once a size was seen twice.
+const SIZES: TableDefinition<u64, &[u8]> = TableDefinition::new("sizes");
+/// The files of each hash, each prefixed with the order it arrived in.
+const HASHES: MultimapTableDefinition<&[u8; 32], &[u8]> = MultimapTableDefinition::new("hashes");
+/// The link count of each hard linked inode and the names found after the
+/// first one.
+const LINKS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("links");
+
+/// Spilled entries are committed in batches of this many, without syncing.
+const COMMIT_EVERY: usize = 10_000;
+
+/// What an in-memory entry costs besides its name: map slot, stamp and
+/// allocation headers, rounded up.
+const ENTRY_OVERHEAD: u64 = 128;
+
+/// The memory the groupings of one scan may use together. Each grouping
+/// moves to disk when an entry takes the total over the limit.
+pub struct Budget {
+    limit: Option<u64>,
+    used: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(limit: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicU64::new(0),
+        })
+    }
+
+    /// Adds `bytes` to the total and tells whether it is now over the limit.
+    fn charge(&self, bytes: u64) -> bool {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.limit.is_some_and(|limit| used > limit)
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The directories of the paths a grouping keeps in memory, each stored
+/// once. Files are kept as the number of their directory and their name.
+#[derive(Default)]
+struct Dirs {
+    ids: HashMap<Arc<Path>, u32>,
+    paths: Vec<Arc<Path>>,
+}
+
+/// A path interned by [`Dirs`].
+struct Name {
+    dir: u32,
+    name: Box<[u8]>,
+}
+
+impl Dirs {
+    /// Interns `path` and returns it with the memory it took.
+    fn intern(&mut self, path: &Path) -> (Name, u64) {
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name.as_bytes()),
+            _ => (Path::new(""), path.as_os_str().as_bytes()),
+        };
+        let mut cost = ENTRY_OVERHEAD + name.len() as u64;
+        let dir = match self.ids.get(dir) {
+            Some(&id) => id,
+            None => {
+                cost += ENTRY_OVERHEAD + dir.as_os_str().len() as u64;
+                let id = self.paths.len() as u32;
+                let dir: Arc<Path> = Arc::from(dir);
+                self.ids.insert(dir.clone(), id);
+                self.paths.push(dir);
+                id
+            }
+        };
+        let name = Name {
+            dir,
+            name: name.into(),
+        };
+        (name, cost)
+    }
+
+    fn resolve(&self, name: &Name) -> PathBuf {
+        self.paths[name.dir as usize].join(OsStr::from_bytes(&name.name))
+    }
+}
+
+/// The sizes seen by a scan. Only the first file of each size is kept, until
+/// a second one shows there is something to hash.
+pub struct SizeIndex {
+    root: PathBuf,
+    memory: HashMap<u64, Option<(Name, FileStamp)>>,
+    dirs: Dirs,
+    spill: Option<SpillDb>,
+    budget: Arc<Budget>,
+    charged: u64,
+}
+
+impl SizeIndex {
+    pub fn new(root: &Path, budget: Arc<Budget>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            memory: HashMap::new(),
+            dirs: Dirs::default(),
+            spill: None,
+            budget,
+            charged: 0,
+        }
+    }
+
+    /// Records `file` and returns the files that now need hashing: none for
+    /// the first file of its size, that file and `file` for the second, and
+    /// `file` alone after that.
+    pub fn add(&mut self, file: ScannedFile) -> Result<Vec<ScannedFile>> {
+        if let Some(spill) = &mut self.spill {
+            let encoded = encode(&self.root, &file)?;
+            let previous = {
+                let mut table = spill.txn()?.open_table(SIZES)?;
+                let previous = table
+                    .get(file.stamp.size)?
+                    .map(|value| value.value().to_vec());
+                match &previous {
+                    None => {
+                        table.insert(file.stamp.size, encoded.as_slice())?;
+                    }
+                    Some(first) if !first.is_empty() => {
+                        table.insert(file.stamp.size, [].as_slice())?;
+                    }
+                    Some(_) => {}
+                }
+                previous
+            };
+            spill.wrote()?;
+            return match previous {
+                None => Ok(Vec::new()),
+                Some(first) if first.is_empty() => Ok(vec![file]),
+                Some(first) => Ok(vec![decode(&self.root, &first)?, file]),
+            };
+        }
+
+        let pending = match self.memory.entry(file.stamp.size) {
+            Entry::Vacant(slot) => {
+                let (name, cost) = self.dirs.intern(&file.path);
+                slot.insert(Some((name, file.stamp)));
+                self.charged += cost;
+                if self.budget.charge(cost) {
+                    self.spill()?;
+                }
+                Vec::new()
+            }
+            Entry::Occupied(mut slot) => match slot.get_mut().take() {
+                Some((name, stamp)) => {
+                    let freed = name.name.len() as u64;
+                    self.charged -= freed;
+                    self.budget.release(freed);
+                    let first = ScannedFile {
+                        path: self.dirs.resolve(&name),
+                        stamp,
+                    };
+                    vec![first, file]
+                }
+                None => vec![file],
+            },
+        };
+        Ok(pending)
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let mut spill = SpillDb::create("sizes")?;
+        for (size, first) in std::mem::take(&mut self.memory) {
+            let encoded = match first {
+                Some((name, stamp)) => {
+                    let path = self.dirs.resolve(&name);
+                    encode(&self.root, &ScannedFile { path, stamp })?
+                }
+                None => Vec::new(),
+            };
+            spill
+                .txn()?
+                .open_table(SIZES)?
+                .insert(size, encoded.as_slice())?;
+            spill.wrote()?;
+        }
+        self.dirs = Dirs::default();
+        self.budget.release(std::mem::take(&mut self.charged));
+        self.spill = Some(spill);
+        Ok(())
+    }
+}
+
+/// The files hashed by a scan, grouped by content hash.
+pub struct HashGroups {
+    root: PathBuf,
+    memory: HashMap<Hash, Vec<(Name, FileStamp)>>,
+    dirs: Dirs,
+    spill: Option<SpillDb>,
+    budget: Arc<Budget>,
+    charged: u64,
+    /// Files added so far. Spilled files are numbered with it, so each group
+    /// reads back in the order its files arrived, as it would from memory,
+    /// and the master does not depend on the memory limit.
+    added: u64,
+}
+
+impl HashGroups {
+    pub fn new(root: &Path, budget: Arc<Budget>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            memory: HashMap::new(),
+            dirs: Dirs::default(),
+            spill: None,
+            budget,
+            charged: 0,
+            added: 0,
+        }
+    }
+
+    pub fn add(&mut self, hash: Hash, file: ScannedFile) -> Result<()> {
+        let seq = self.added;
+        self.added += 1;
+        if let Some(spill) = &mut self.spill {
+            let encoded = encode_numbered(seq, &self.root, &file)?;
+            spill
+                .txn()?
+                .open_multimap_table(HASHES)?
+                .insert(&hash, encoded.as_slice())?;
+            return spill.wrote();
+        }
+
+        let (name, cost) = self.dirs.intern(&file.path);
+        self.memory
+            .entry(hash)
+            .or_default()
+            .push((name, file.stamp));
+        self.charged += cost;
+        if self.budget.charge(cost) {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the grouping. Files alone with their hash have nothing to be
+    /// deduplicated against and are dropped.
+    pub fn finish(mut self) -> Result<Duplicates> {
+        self.memory.retain(|_, files| files.len() > 1);
+        let spill = self.spill.take().map(SpillDb::finish).transpose()?;
+        Ok(Duplicates {
+            root: self.root,
+            memory: self.memory,
+            dirs: self.dirs,
+            spill,
+            budget: self.budget,
+            charged: self.charged,
+        })
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let mut spill = SpillDb::create("hashes")?;
+        let mut seq = 0;
+        for (hash, files) in std::mem::take(&mut self.memory) {
+            for (name, stamp) in files {
+                let path = self.dirs.resolve(&name);
+                let encoded = encode_numbered(seq, &self.root, &ScannedFile { path, stamp })?;
+                seq += 1;
+                spill
+                    .txn()?
+                    .open_multimap_table(HASHES)?
+                    .insert(&hash, encoded.as_slice())?;
+                spill.wrote()?;
+            }
+        }
+        self.dirs = Dirs::default();
+        self.budget.release(std::mem::take(&mut self.charged));
+        self.spill = Some(spill);
+        Ok(())
+    }
+}
+
+/// The duplicate groups of a finished scan.
+pub struct Duplicates {
+    root: PathBuf,
+    memory: HashMap<Hash, Vec<(Name, FileStamp)>>,
+    dirs: Dirs,
+    spill: Option<SpillFile>,
+    budget: Arc<Budget>,
+    charged: u64,
+}
+
+impl Duplicates {
+    /// Passes every group to `each`, one hash at a time, with its files in
+    /// the order they were hashed. Spilled groups are read back from disk
+    /// as they are passed on.
+    pub fn for_each(
+        mut self,
+        mut each: impl FnMut(Hash, Vec<ScannedFile>) -> Result<()>,
+    ) -> Result<()> {
+        for (hash, files) in std::mem::take(&mut self.memory) {
+            let files = files
+                .into_iter()
+                .map(|(name, stamp)| ScannedFile {
+                    path: self.dirs.resolve(&name),
+                    stamp,
+                })
+                .collect();
+            each(hash, files)?;
+        }
+        let Some(spill) = &self.spill else {
+            return Ok(());
+        };
+
+        let read = spill
+            .db
+            .begin_read()
+            .with_context(|| "read spill database")?;
+        let table = read.open_multimap_table(HASHES)?;
+        for entry in table.iter()? {
+            let (hash, values) = entry?;
+            if values.len() < 2 {
+                continue;
+            }
+            let files = values
+                .map(|value| decode(&self.root, &value?.value()[8..]))
+                .collect::<Result<Vec<_>>>()?;
+            each(*hash.value(), files)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Duplicates {
+    fn drop(&mut self) {
+        self.budget.release(self.charged);
+    }
+}
+
+/// The hard linked files found by a scan: the link count of each, and its
+/// names under the root after the first one.
+pub struct HardLinks {
+    root: PathBuf,
+    memory: HashMap<InodeId, (u64, Vec<Name>)>,
+    dirs: Dirs,
+    spill: Option<SpillDb>,
+    budget: Arc<Budget>,
+    charged: u64,
+}
+
+impl HardLinks {
+    pub fn new(root: &Path, budget: Arc<Budget>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            memory: HashMap::new(),
+            dirs: Dirs::default(),
+            spill: None,
+            budget,
+            charged: 0,
+        }
+    }
+
+    /// Records `file` and returns it if it is to be hashed: a file with a
+    /// single name, or the first name found of a hard linked one. Later
+    /// names of that file are kept as its aliases instead.
+    pub fn add(&mut self, file: ScannedFile) -> Result<Option<ScannedFile>> {
+        if file.stamp.nlink < 2 {
+            return Ok(Some(file));
+        }
+        let inode = file.stamp.inode();
+
+        if let Some(spill) = &mut self.spill {
+            let key = inode_key(inode);
+            let first = {
+                let mut table = spill.txn()?.open_table(LINKS)?;
+                let previous = table
+                    .get(key.as_slice())?
+                    .map(|value| value.value().to_vec());
+                let links = match &previous {
+                    None => (file.stamp.nlink, Vec::new()),
+                    Some(bytes) => {
+                        let (nlink, mut aliases): (u64, Vec<Vec<u8>>) =
+                            bincode::deserialize(bytes).with_context(|| "decode spilled links")?;
+                        aliases.push(relative(&self.root, &file.path).to_vec());
+                        (nlink, aliases)
+                    }
+                };
+                let encoded = bincode::serialize(&links).with_context(|| "encode spilled links")?;
+                table.insert(key.as_slice(), encoded.as_slice())?;
+                previous.is_none()
+            };
+            spill.wrote()?;
+            return Ok(first.then_some(file));
+        }
+
+        let (first, cost) = match self.memory.entry(inode) {
+            Entry::Vacant(slot) => {
+                slot.insert((file.stamp.nlink, Vec::new()));
+                (true, ENTRY_OVERHEAD)
+            }
+            Entry::Occupied(mut slot) => {
+                let (name, cost) = self.dirs.intern(&file.path);
+                slot.get_mut().1.push(name);
+                (false, cost)
+            }
+        };
+        self.charged += cost;
+        if self.budget.charge(cost) {
+            self.spill()?;
+        }
+        Ok(first.then_some(file))
+    }
+
+    /// Ends the scan's records of hard links, to be looked up from here on.
+    pub fn finish(self) -> Result<HardLinkIndex> {
+        let spill = self.spill.map(SpillDb::finish).transpose()?;
+        Ok(HardLinkIndex {
+            root: self.root,
+            memory: self.memory,
+            dirs: self.dirs,
+            spill,
+            budget: self.budget,
+            charged: self.charged,
+        })
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let mut spill = SpillDb::create("links")?;
+        for (inode, (nlink, names)) in std::mem::take(&mut self.memory) {
+            let aliases: Vec<Vec<u8>> = names
+                .iter()
+                .map(|name| relative(&self.root, &self.dirs.resolve(name)).to_vec())
+                .collect();
+            let encoded =
+                bincode::serialize(&(nlink, aliases)).with_context(|| "encode spilled links")?;
+            spill
+                .txn()?
+                .open_table(LINKS)?
+                .insert(inode_key(inode).as_slice(), encoded.as_slice())?;
+            spill.wrote()?;
+        }
+        self.dirs = Dirs::default();
+        self.budget.release(std::mem::take(&mut self.charged));
+        self.spill = Some(spill);
+        Ok(())
+    }
+}
+
+/// The hard links of a finished scan.
+pub struct HardLinkIndex {
+    root: PathBuf,
+    memory: HashMap<InodeId, (u64, Vec<Name>)>,
+    dirs: Dirs,
+    spill: Option<SpillFile>,
+    budget: Arc<Budget>,
+    charged: u64,
+}
+
+impl HardLinkIndex {
+    /// The other names under the root of the file `stamp` describes, and
+    /// whether it also has names outside the root.
+    pub fn names(&self, stamp: &FileStamp) -> Result<(Vec<PathBuf>, bool)> {
+        if stamp.nlink < 2 {
+            return Ok((Vec::new(), false));
+        }
+        let (nlink, aliases) = match &self.spill {
+            Some(spill) => {
+                let read = spill
+                    .db
+                    .begin_read()
+                    .with_context(|| "read spill database")?;
+                let table = read.open_table(LINKS)?;
+                let Some(value) = table.get(inode_key(stamp.inode()).as_slice())? else {
+                    return Ok((Vec::new(), false));
+                };
+                let (nlink, aliases): (u64, Vec<Vec<u8>>) =
+                    bincode::deserialize(value.value()).with_context(|| "decode spilled links")?;
+                let aliases = aliases
+                    .iter()
+                    .map(|alias| self.root.join(OsStr::from_bytes(alias)))
+                    .collect::<Vec<_>>();
+                (nlink, aliases)
+            }
+            None => match self.memory.get(&stamp.inode()) {
+                Some((nlink, names)) => {
+                    let aliases = names.iter().map(|name| self.dirs.resolve(name)).collect();
+                    (*nlink, aliases)
+                }
+                None => return Ok((Vec::new(), false)),
+            },
+        };
+        let outside = nlink > 1 + aliases.len() as u64;
+        Ok((aliases, outside))
+    }
+}
+
+impl Drop for HardLinkIndex {
+    fn drop(&mut self) {
+        self.budget.release(self.charged);
+    }
+}
+
+/// A scratch redb database in an unnamed file in the data directory, so it
+/// is gone once closed, even if the process is killed.
+struct SpillFile {
+    db: Database,
+}
+
+/// A [`SpillFile`] being written. Writes go through one open transaction
+/// that is committed every [`COMMIT_EVERY`] entries, so the pages it dirties
+/// can be written out.
+struct SpillDb {
+    // Declared before `file`: an open transaction must be dropped first.
+    txn: Option<WriteTransaction>,
+    file: SpillFile,
+    uncommitted: usize,
+}
+
+impl SpillDb {
+    fn create(name: &str) -> Result<Self> {
+        let dir = crate::state::data_dir()?;
+        std::fs::create_dir_all(&dir).with_context(|| "create data directory")?;
+        let file = tempfile::tempfile_in(&dir)
+            .with_context(|| format!("create spill file for {name} in {dir:?}"))?;
+        let db = Database::builder()
+            .create_file(file)
+            .with_context(|| "create spill database")?;
+        Ok(Self {
+            txn: None,
+            file: SpillFile { db },
+            uncommitted: 0,
+        })
+    }
+
+    fn txn(&mut self) -> Result<&WriteTransaction> {
+        let txn = match self.txn.take() {
+            Some(txn) => txn,
+            None => {
+                let mut txn = self
+                    .file
+                    .db
+                    .begin_write()
+                    .with_context(|| "write spill database")?;
+                txn.set_durability(Durability::None);
+                txn
+            }
+        };
+        Ok(self.txn.insert(txn))
+    }
+
+    fn wrote(&mut self) -> Result<()> {
+        self.uncommitted += 1;
+        if self.uncommitted >= COMMIT_EVERY {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if let Some(txn) = self.txn.take() {
+            txn.commit().with_context(|| "commit spill database")?;
+        }
+        self.uncommitted = 0;
+        Ok(())
+    }
+
+    /// Commits what was written, leaving the database to be read.
+    fn finish(mut self) -> Result<SpillFile> {
+        self.commit()?;
+        Ok(self.file)
+    }
+}
+
+/// `path` relative to `root` when it is under it. Relative paths never
+/// start with `/`, absolute ones always do.
+fn relative<'a>(root: &Path, path: &'a Path) -> &'a [u8] {
+    path.strip_prefix(root)
+        .ok()
+        .filter(|relative| !relative.as_os_str().is_empty())
+        .unwrap_or(path)
+        .as_os_str()
+        .as_bytes()
+}
+
+/// Stores the stamp and the path of `file`, relative to `root` when it is
+/// under it.
+fn encode(root: &Path, file: &ScannedFile) -> Result<Vec<u8>> {
+    bincode::serialize(&(file.stamp, relative(root, &file.path)))
+        .with_context(|| "encode spilled file")
+}
+
+/// Like [`encode`], after `seq` in big-endian so entries sort by it.
+fn encode_numbered(seq: u64, root: &Path, file: &ScannedFile) -> Result<Vec<u8>> {
+    let mut encoded = seq.to_be_bytes().to_vec();
+    encoded.extend(encode(root, file)?);
+    Ok(encoded)
+}
+
+fn decode(root: &Path, bytes: &[u8]) -> Result<ScannedFile> {
+    let (stamp, path): (FileStamp, Vec<u8>) =
+        bincode::deserialize(bytes).with_context(|| "decode spilled file")?;
+    let path = Path::new(OsStr::from_bytes(&path));
+    Ok(ScannedFile {
+        path: root.join(path),
+        stamp,
+    })
+}
@@ -0,0 +1,303 @@
+use crate::dedupe::{self, Swap, TempCleanup};
+use crate::hasher;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// A file being prepared to replace, or be placed at, some path.
+///
+/// Where the filesystem supports `O_TMPFILE` the file has no name until it is
+/// persisted or swapped in, so other tools never see it and a crash leaves
+/// nothing behind. Elsewhere it is a `*.imprint_tmp` sibling that is removed
+/// on drop.
+pub struct StagedFile {
+    file: File,
+    /// Set when the file is a named temp rather than an anonymous one.
+    named: Option<(PathBuf, TempCleanup)>,
+}
+
+impl StagedFile {
+    /// Creates an empty staging file in the directory of `target`.
+    pub fn create(target: &Path) -> Result<Self> {
+        if let Some(file) = open_anonymous(target)? {
+            return Ok(Self { file, named: None });
+        }
+
+        let temp = dedupe::temp_path(target);
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .with_context(|| "create temp file")?;
+        let cleanup = TempCleanup::new(temp.clone());
+        Ok(Self {
+            file,
+            named: Some((temp, cleanup)),
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Fills the file with a reflink clone of `source`.
+    pub fn clone_from(&mut self, source: &Path) -> Result<()> {
+        clone_file(source, self)
+    }
+
+    /// Fills the file with a copy of `source`, permissions included. Holes
+    /// in `source` stay holes, and data is copied inside the kernel where
+    /// `copy_file_range` is supported.
+    pub fn copy_from(&mut self, source: &Path) -> Result<()> {
+        let src = hasher::open_noatime(source).with_context(|| "open copy source")?;
+        let meta = src
+            .metadata()
+            .with_context(|| "read copy source metadata")?;
+        copy_sparse(&src, &self.file, meta.len()).with_context(|| "copy bytes to temp file")?;
+        self.file
+            .set_permissions(meta.permissions())
+            .with_context(|| "copy file permissions")
+    }
+
+    /// Gives the file the name `dest`, which must not exist yet.
+    pub fn persist(self, dest: &Path) -> Result<()> {
+        match self.named {
+            Some((temp, mut cleanup)) => {
+                std::fs::rename(&temp, dest).with_context(|| "move temp file into place")?;
+                cleanup.disarm();
+                Ok(())
+            }
+            None => link_anonymous(&self.file, dest),
+        }
+    }
+
+    /// Swaps the file in place of `target`; see [`Swap::exchange`]. An
+    /// anonymous file only gets its temp name right before the exchange.
+    pub fn swap_into(self, target: &Path) -> Result<Swap> {
+        let (temp, mut cleanup) = match self.named {
+            Some(named) => named,
+            None => {
+                let temp = dedupe::temp_path(target);
+                link_anonymous(&self.file, &temp)?;
+                let cleanup = TempCleanup::new(temp.clone());
+                (temp, cleanup)
+            }
+        };
+        let swap = Swap::exchange(&temp, target)?;
+        cleanup.disarm();
+        Ok(swap)
+    }
+}
+
+/// Opens an unnamed file in the directory of `target`, or returns `None` if
+/// the platform or filesystem cannot.
+#[cfg(target_os = "linux")]
+fn open_anonymous(target: &Path) -> Result<Option<File>> {
+    use nix::libc;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match File::options()
+        .read(true)
+        .write(true)
+        .mode(0o666)
+        .custom_flags(libc::O_TMPFILE)
+        .open(dir)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(err)
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::EOPNOTSUPP | libc::EISDIR | libc::EINVAL | libc::ENOENT)
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err).with_context(|| "create anonymous temp file"),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_anonymous(_target: &Path) -> Result<Option<File>> {
+    Ok(None)
+}
+
+/// Links an `O_TMPFILE` file at `dest` through its `/proc` entry, which does
+/// not need `CAP_DAC_READ_SEARCH` the way `AT_EMPTY_PATH` does.
+#[cfg(target_os = "linux")]
+fn link_anonymous(file: &File, dest: &Path) -> Result<()> {
+    use nix::unistd::{LinkatFlags, linkat};
+    use std::os::unix::io::AsRawFd;
+
+    let proc_path = format!("/proc/self/fd/{}", file.as_raw_fd());
+    linkat(
+        None,
+        Path::new(&proc_path),
+        None,
+        dest,
+        LinkatFlags::SymlinkFollow,
+    )
+    .with_context(|| "link temp file into place")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn link_anonymous(_file: &File, _dest: &Path) -> Result<()> {
+    unreachable!("anonymous temp files are only created on Linux")
+}
+
+#[cfg(target_os = "linux")]
+fn clone_file(source: &Path, staged: &mut StagedFile) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+    let src = File::open(source).with_context(|| "open clone source")?;
+
+    // SAFETY: FICLONE takes the source descriptor as its argument; both
+    // descriptors stay open for the duration of the call.
+    unsafe { ficlone(staged.file.as_raw_fd(), src.as_raw_fd() as _) }
+        .with_context(|| "reflink not supported")?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(source: &Path, staged: &mut StagedFile) -> Result<()> {
+    // The reflink crate creates the destination itself, so the named temp is
+    // recreated by it and reopened.
+    let (temp, _) = staged
+        .named
+        .as_ref()
+        .context("anonymous temp files are only created on Linux")?;
+    std::fs::remove_file(temp).with_context(|| "remove temp file before clone")?;
+    reflink::reflink(source, temp).with_context(|| "reflink not supported")?;
+    staged.file = File::options()
+        .read(true)
+        .write(true)
+        .open(temp)
+        .with_context(|| "reopen cloned temp file")?;
+    Ok(())
+}
+
+/// Copies the first `len` bytes of `src` into `dst`, skipping holes.
+#[cfg(target_os = "linux")]
+fn copy_sparse(src: &File, dst: &File, len: u64) -> Result<()> {
+    let mut offset = 0;
+    while offset < len {
+        let Some((start, end)) = next_data(src, offset, len)? else {
+            break;
+        };
+        copy_range(src, dst, start, end)?;
+        offset = end;
+    }
+    // Trailing holes have no data to copy but still count towards the length.
+    dst.set_len(len).with_context(|| "set copy length")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_sparse(src: &File, mut dst: &File, len: u64) -> Result<()> {
+    std::io::copy(&mut src.take(len), &mut dst).with_context(|| "copy bytes")?;
+    Ok(())
+}
+
+/// Finds the next run of data at or after `offset`, or `None` when only a
+/// hole is left. Filesystems without hole reporting yield one run to `len`.
+#[cfg(target_os = "linux")]
+fn next_data(file: &File, offset: u64, len: u64) -> Result<Option<(u64, u64)>> {
+    use nix::errno::Errno;
+    use nix::unistd::{Whence, lseek};
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let start = match lseek(fd, offset as i64, Whence::SeekData) {
+        Ok(start) => start as u64,
+        Err(Errno::ENXIO) => return Ok(None),
+        Err(Errno::EINVAL | Errno::EOPNOTSUPP) => return Ok(Some((offset, len))),
+        Err(err) => return Err(err).with_context(|| "seek to data"),
+    };
+    let end = match lseek(fd, start as i64, Whence::SeekHole) {
+        Ok(end) => (end as u64).min(len),
+        Err(_) => len,
+    };
+    Ok(Some((start, end)))
+}
+
+/// Copies `start..end` to the same offsets in `dst`, through the kernel when
+/// possible.
+#[cfg(target_os = "linux")]
+fn copy_range(src: &File, dst: &File, start: u64, end: u64) -> Result<()> {
+    use nix::errno::Errno;
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    const MAX_CHUNK: u64 = 1 << 30;
+
+    let mut off_in = start as i64;
+    let mut off_out = start as i64;
+    while (off_in as u64) < end {
+        let chunk = (end - off_in as u64).min(MAX_CHUNK) as usize;
+        // nix 0.27 passes the wrong value for the source descriptor, so the
+        // libc wrapper is called directly.
+        // SAFETY: both descriptors stay open for the call and the offsets
+        // point at live locals.
+        let res = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut off_in,
+                dst.as_raw_fd(),
+                &mut off_out,
+                chunk,
+                0,
+            )
+        };
+        match Errno::result(res) {
+            // The source got shorter than its metadata said.
+            Ok(0) => anyhow::bail!("source truncated during copy"),
+            Ok(_) => {}
+            Err(Errno::EXDEV | Errno::ENOSYS | Errno::EINVAL | Errno::EOPNOTSUPP) => {
+                return copy_range_buffered(src, dst, off_in as u64, end);
+            }
+            Err(err) => return Err(err).with_context(|| "copy_file_range"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn copy_range_buffered(src: &File, dst: &File, start: u64, end: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    const BUFFER_SIZE: usize = 128 * 1024;
+
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut offset = start;
+    while offset < end {
+        let want = (end - offset).min(BUFFER_SIZE as u64) as usize;
+        let read = src
+            .read_at(&mut buffer[..want], offset)
+            .with_context(|| "read source")?;
+        if read == 0 {
+            anyhow::bail!("source truncated during copy");
+        }
+        dst.write_all_at(&buffer[..read], offset)
+            .with_context(|| "write copy")?;
+        offset += read as u64;
+    }
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(target_os = "linux")]
+pub fn available_space(path: &Path) -> Result<u64> {
+    let stats = nix::sys::statvfs::statvfs(path).with_context(|| "read free space")?;
+    Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64)
+}
+
+/// Free space is not checked elsewhere; every write is assumed to fit.
+#[cfg(not(target_os = "linux"))]
+pub fn available_space(_path: &Path) -> Result<u64> {
+    Ok(u64::MAX)
+}
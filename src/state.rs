@@ -1,27 +1,60 @@
-use crate::types::{FileMetadata, Hash};
+use crate::change::FileStamp;
+use crate::dedupe::SavedMetadata;
+use crate::migrate::{self, Upgrade};
+use crate::run::{RunFile, RunInfo};
+use crate::types::{FileMetadata, Hash, InodeId};
 use anyhow::{Context, Result};
 use crossbeam::channel::Receiver;
-use redb::{Database, TableDefinition};
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 const FILE_INDEX: TableDefinition<&[u8], &[u8]> = TableDefinition::new("file_index");
 const CAS_INDEX: TableDefinition<&[u8], &[u8]> = TableDefinition::new("cas_index");
+/// Inodes hard linked to a vault object, keyed by device then inode.
 const VAULTED_INODES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("vaulted_inodes");
+const ORIGINAL_METADATA: TableDefinition<&[u8], &[u8]> = TableDefinition::new("original_metadata");
+/// Indexed paths by the inode they led to when indexed: device and inode
+/// followed by the path. Values are empty.
+const INODE_PATHS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("inode_paths");
+/// Dedupe runs by big-endian ID, so the last entry is the latest run.
+const RUNS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("runs");
+/// Files replaced by each run, keyed by run ID followed by the path. Entries
+/// are only ever added, so a run can be undone long after it finished.
+const RUN_FILES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("run_files");
+/// The runs that recorded each path: the path, a zero byte and the
+/// big-endian run ID. Values are empty. Lets a moved file's run records be
+/// found without reading every run.
+const RUN_PATHS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("run_paths");
+/// Small named values that belong to the database as a whole.
+pub(crate) const META: TableDefinition<&str, &[u8]> = TableDefinition::new("meta");
 const BATCH_SIZE: usize = 1000;
 
 #[derive(Clone, Debug)]
 pub enum DbOp {
     UpsertFile(PathBuf, FileMetadata),
     SetCasRefcount(Hash, u64),
-    MarkInodeVaulted(u64),
+    MarkInodeVaulted(InodeId),
     RemoveFileFromIndex(PathBuf),
-    UnmarkInodeVaulted(u64),
+    /// Moves the records of a file that was moved or renamed to `path`, if
+    /// `path` is not indexed yet: they are taken from an indexed name of the
+    /// same inode that no longer leads to it, and whose record still has the
+    /// size and modification time of the stamp.
+    FollowMove(PathBuf, FileStamp),
+    UnmarkInodeVaulted(InodeId),
     RemoveCasRefcount(Hash),
+    SaveOriginalMetadata(PathBuf, SavedMetadata),
+    RemoveOriginalMetadata(PathBuf),
+    RecordRunFile(u64, PathBuf, RunFile),
 }
 
 #[derive(Clone)]
 pub struct State {
     db: std::sync::Arc<Database>,
+    // Declared after `db`: a dry run's database lives in a directory the
+    // upgrade removes.
+    upgrade: Option<std::sync::Arc<Upgrade>>,
 }
 
 #[allow(dead_code)]
@@ -48,10 +81,17 @@ impl State {
             let _ = txn.open_table(FILE_INDEX)?;
             let _ = txn.open_table(CAS_INDEX)?;
             let _ = txn.open_table(VAULTED_INODES)?;
+            let _ = txn.open_table(INODE_PATHS)?;
+            let _ = txn.open_table(ORIGINAL_METADATA)?;
+            let _ = txn.open_table(RUNS)?;
+            let _ = txn.open_table(RUN_FILES)?;
+            let _ = txn.open_table(RUN_PATHS)?;
+            let _ = txn.open_table(META)?;
         }
         txn.commit()?;
         Ok(Self {
             db: std::sync::Arc::new(db),
+            upgrade: None,
         })
     }
 
@@ -66,6 +106,7 @@ impl State {
         } else {
             Database::create(&db_path).with_context(|| "open redb database")?
         };
+        let (db, upgrade) = migrate::upgrade(db, &db_path, readonly)?;
         let txn = db
             .begin_write()
             .with_context(|| "begin write transaction")?;
@@ -73,27 +114,28 @@ impl State {
             let _ = txn.open_table(FILE_INDEX)?;
             let _ = txn.open_table(CAS_INDEX)?;
             let _ = txn.open_table(VAULTED_INODES)?;
+            let _ = txn.open_table(INODE_PATHS)?;
+            let _ = txn.open_table(ORIGINAL_METADATA)?;
+            let _ = txn.open_table(RUNS)?;
+            let _ = txn.open_table(RUN_FILES)?;
+            let _ = txn.open_table(RUN_PATHS)?;
+            let _ = txn.open_table(META)?;
         }
         txn.commit()
             .with_context(|| "commit table initialization")?;
         Ok(Self {
             db: std::sync::Arc::new(db),
+            upgrade: upgrade.map(std::sync::Arc::new),
         })
     }
 
+    /// The schema upgrade applied when the database was opened, if any.
+    pub fn upgrade(&self) -> Option<&Upgrade> {
+        self.upgrade.as_deref()
+    }
+
     pub fn upsert_file(&self, path: &Path, metadata: &FileMetadata) -> Result<()> {
-        let key = path.to_string_lossy().as_bytes().to_vec();
-        let value = bincode::serialize(metadata).with_context(|| "serialize file metadata")?;
-        let txn = self
-            .db
-            .begin_write()
-            .with_context(|| "begin write transaction")?;
-        {
-            let mut table = txn.open_table(FILE_INDEX)?;
-            table.insert(key.as_slice(), value.as_slice())?;
-        }
-        txn.commit().with_context(|| "commit file index write")?;
-        Ok(())
+        self.batch_write(vec![DbOp::UpsertFile(path.to_path_buf(), metadata.clone())])
     }
 
     pub fn set_cas_refcount(&self, hash: &Hash, count: u64) -> Result<()> {
@@ -111,8 +153,8 @@ impl State {
         Ok(())
     }
 
-    pub fn is_inode_vaulted(&self, inode: u64) -> Result<bool> {
-        let key = inode.to_le_bytes();
+    pub fn is_inode_vaulted(&self, inode: InodeId) -> Result<bool> {
+        let key = inode_key(inode);
         let txn = self
             .db
             .begin_read()
@@ -125,8 +167,8 @@ impl State {
         Ok(table.get(key.as_slice())?.is_some())
     }
 
-    pub fn mark_inode_vaulted(&self, inode: u64) -> Result<()> {
-        let key = inode.to_le_bytes();
+    pub fn mark_inode_vaulted(&self, inode: InodeId) -> Result<()> {
+        let key = inode_key(inode);
         let value = 1u8;
         let txn = self
             .db
@@ -141,7 +183,7 @@ impl State {
     }
 
     pub fn get_file_metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
-        let key = path.to_string_lossy().as_bytes().to_vec();
+        let key = path_key(path).to_vec();
         let txn = self
             .db
             .begin_read()
@@ -152,29 +194,252 @@ impl State {
             Err(err) => return Err(err.into()),
         };
         if let Some(access) = table.get(key.as_slice())? {
-            let metadata: FileMetadata = bincode::deserialize(access.value())
-                .with_context(|| "deserialize file metadata")?;
+            let metadata: FileMetadata =
+                migrate::decode(access.value()).with_context(|| "deserialize file metadata")?;
             return Ok(Some(metadata));
         }
         Ok(None)
     }
 
-    pub fn remove_file_from_index(&self, path: &Path) -> Result<()> {
-        let key = path.to_string_lossy().as_bytes().to_vec();
+    /// Like [`State::get_file_metadata`], but `path` may be relative or lead
+    /// through symlinked directories.
+    pub fn lookup_file(&self, path: &Path) -> Result<Option<FileMetadata>> {
+        self.get_file_metadata(&canonical_path(path)?)
+    }
+
+    /// Indexed paths of `inode` that no longer lead to it: where `path` may
+    /// have been moved or renamed from. Empty if `path` is indexed itself.
+    pub fn moved_from(&self, path: &Path, inode: InodeId) -> Result<Vec<PathBuf>> {
+        let txn = self
+            .db
+            .begin_read()
+            .with_context(|| "begin read transaction")?;
+        match txn.open_table(INODE_PATHS) {
+            Ok(table) => vanished_paths(&table, path, inode),
+            Err(redb::TableError::TableDoesNotExist(_)) => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The record of the file at `path`, and the path it is kept under:
+    /// `path` itself, or the path it had before it was moved or renamed. A
+    /// record under another path only counts if it matches `stamp`.
+    pub fn find_record(
+        &self,
+        path: &Path,
+        stamp: &FileStamp,
+    ) -> Result<Option<(PathBuf, FileMetadata)>> {
+        let path = canonical_path(path)?;
+        if let Some(metadata) = self.get_file_metadata(&path)? {
+            return Ok(Some((path, metadata)));
+        }
+        for previous in self.moved_from(&path, stamp.inode())? {
+            if let Some(metadata) = self.get_file_metadata(&previous)?
+                && stamp.matches(&metadata)
+            {
+                return Ok(Some((previous, metadata)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Metadata `path` had before it was first deduplicated, if recorded.
+    pub fn get_original_metadata(&self, path: &Path) -> Result<Option<SavedMetadata>> {
+        let key = path_key(path).to_vec();
+        let txn = self
+            .db
+            .begin_read()
+            .with_context(|| "begin read transaction")?;
+        let table = match txn.open_table(ORIGINAL_METADATA) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(access) = table.get(key.as_slice())? {
+            let metadata: SavedMetadata =
+                migrate::decode(access.value()).with_context(|| "deserialize original metadata")?;
+            return Ok(Some(metadata));
+        }
+        Ok(None)
+    }
+
+    /// Every indexed path whose content hashed to `hash`.
+    pub fn files_with_hash(&self, hash: &Hash) -> Result<Vec<PathBuf>> {
+        let txn = self
+            .db
+            .begin_read()
+            .with_context(|| "begin read transaction")?;
+        let table = match txn.open_table(FILE_INDEX) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut paths = Vec::new();
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            let metadata: FileMetadata =
+                migrate::decode(value.value()).with_context(|| "deserialize file metadata")?;
+            if metadata.hash == *hash {
+                paths.push(path_from_key(key.value()));
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Records the start of a dedupe run and returns its ID.
+    pub fn begin_run(&self, info: &RunInfo) -> Result<u64> {
+        let value = migrate::encode(info).with_context(|| "serialize run info")?;
+        let txn = self
+            .db
+            .begin_write()
+            .with_context(|| "begin write transaction")?;
+        let id = {
+            let mut table = txn.open_table(RUNS)?;
+            let id = match table.last()? {
+                Some((key, _)) => run_id_from_key(key.value())? + 1,
+                None => 1,
+            };
+            table.insert(id.to_be_bytes().as_slice(), value.as_slice())?;
+            id
+        };
+        txn.commit().with_context(|| "commit run start")?;
+        Ok(id)
+    }
+
+    pub fn put_run(&self, id: u64, info: &RunInfo) -> Result<()> {
+        let value = migrate::encode(info).with_context(|| "serialize run info")?;
         let txn = self
             .db
             .begin_write()
             .with_context(|| "begin write transaction")?;
         {
-            let mut table = txn.open_table(FILE_INDEX)?;
-            table.remove(key.as_slice())?;
+            let mut table = txn.open_table(RUNS)?;
+            table.insert(id.to_be_bytes().as_slice(), value.as_slice())?;
         }
-        txn.commit().with_context(|| "commit file index removal")?;
+        txn.commit().with_context(|| "commit run update")?;
         Ok(())
     }
 
-    pub fn unmark_inode_vaulted(&self, inode: u64) -> Result<()> {
-        let key = inode.to_le_bytes();
+    /// Every recorded run, oldest first.
+    pub fn list_runs(&self) -> Result<Vec<(u64, RunInfo)>> {
+        let txn = self
+            .db
+            .begin_read()
+            .with_context(|| "begin read transaction")?;
+        let table = match txn.open_table(RUNS) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut runs = Vec::new();
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            let info: RunInfo =
+                migrate::decode(value.value()).with_context(|| "deserialize run info")?;
+            runs.push((run_id_from_key(key.value())?, info));
+        }
+        Ok(runs)
+    }
+
+    pub fn get_run(&self, id: u64) -> Result<Option<RunInfo>> {
+        let txn = self
+            .db
+            .begin_read()
+            .with_context(|| "begin read transaction")?;
+        let table = match txn.open_table(RUNS) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(access) = table.get(id.to_be_bytes().as_slice())? {
+            let info: RunInfo =
+                migrate::decode(access.value()).with_context(|| "deserialize run info")?;
+            return Ok(Some(info));
+        }
+        Ok(None)
+    }
+
+    /// The files run `id` replaced.
+    pub fn run_files(&self, id: u64) -> Result<Vec<(PathBuf, RunFile)>> {
+        let txn = self
+            .db
+            .begin_read()
+            .with_context(|| "begin read transaction")?;
+        let table = match txn.open_table(RUN_FILES) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let start = id.to_be_bytes();
+        let end = (id + 1).to_be_bytes();
+        let mut files = Vec::new();
+        for entry in table.range(start.as_slice()..end.as_slice())? {
+            let (key, value) = entry?;
+            let path = path_from_key(&key.value()[8..]);
+            let file: RunFile =
+                migrate::decode(value.value()).with_context(|| "deserialize run file")?;
+            files.push((path, file));
+        }
+        Ok(files)
+    }
+
+    /// Replaces the tables that can be regenerated from the vault and the
+    /// filesystem with what `ops` write, in one transaction so a failure
+    /// leaves the previous index. Run history and original metadata are kept.
+    pub fn replace_index(&self, ops: Vec<DbOp>) -> Result<()> {
+        let txn = self
+            .db
+            .begin_write()
+            .with_context(|| "begin write transaction")?;
+        for table in [FILE_INDEX, CAS_INDEX, VAULTED_INODES, INODE_PATHS] {
+            txn.delete_table(table)?;
+            txn.open_table(table)?;
+        }
+        write_ops(&txn, ops)?;
+        txn.commit().with_context(|| "commit index rebuild")?;
+        Ok(())
+    }
+
+    pub fn get_meta(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let txn = self
+            .db
+            .begin_read()
+            .with_context(|| "begin read transaction")?;
+        let table = match txn.open_table(META) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(table.get(key)?.map(|access| access.value().to_vec()))
+    }
+
+    /// Stores `value` under `key`, or removes the key when `value` is `None`.
+    pub fn set_meta(&self, key: &str, value: Option<&[u8]>) -> Result<()> {
+        let txn = self
+            .db
+            .begin_write()
+            .with_context(|| "begin write transaction")?;
+        {
+            let mut table = txn.open_table(META)?;
+            match value {
+                Some(value) => {
+                    table.insert(key, value)?;
+                }
+                None => {
+                    table.remove(key)?;
+                }
+            }
+        }
+        txn.commit().with_context(|| "commit meta write")?;
+        Ok(())
+    }
+
+    pub fn remove_file_from_index(&self, path: &Path) -> Result<()> {
+        self.batch_write(vec![DbOp::RemoveFileFromIndex(path.to_path_buf())])
+    }
+
+    pub fn unmark_inode_vaulted(&self, inode: InodeId) -> Result<()> {
+        let key = inode_key(inode);
         let txn = self
             .db
             .begin_write()
@@ -230,52 +495,16 @@ impl State {
             .db
             .begin_write()
             .with_context(|| "begin batch write transaction")?;
-        {
-            for op in ops {
-                match op {
-                    DbOp::UpsertFile(path, metadata) => {
-                        let key = path.to_string_lossy().as_bytes().to_vec();
-                        let value = bincode::serialize(&metadata)
-                            .with_context(|| "serialize file metadata")?;
-                        let mut table = txn.open_table(FILE_INDEX)?;
-                        table.insert(key.as_slice(), value.as_slice())?;
-                    }
-                    DbOp::SetCasRefcount(hash, count) => {
-                        let key = hash.to_vec();
-                        let value = count.to_le_bytes().to_vec();
-                        let mut table = txn.open_table(CAS_INDEX)?;
-                        table.insert(key.as_slice(), value.as_slice())?;
-                    }
-                    DbOp::MarkInodeVaulted(inode) => {
-                        let key = inode.to_le_bytes();
-                        let value = 1u8;
-                        let mut table = txn.open_table(VAULTED_INODES)?;
-                        table.insert(key.as_slice(), std::slice::from_ref(&value))?;
-                    }
-                    DbOp::RemoveFileFromIndex(path) => {
-                        let key = path.to_string_lossy().as_bytes().to_vec();
-                        let mut table = txn.open_table(FILE_INDEX)?;
-                        table.remove(key.as_slice())?;
-                    }
-                    DbOp::UnmarkInodeVaulted(inode) => {
-                        let key = inode.to_le_bytes();
-                        let mut table = txn.open_table(VAULTED_INODES)?;
-                        table.remove(key.as_slice())?;
-                    }
-                    DbOp::RemoveCasRefcount(hash) => {
-                        let key = hash.to_vec();
-                        let mut table = txn.open_table(CAS_INDEX)?;
-                        table.remove(key.as_slice())?;
-                    }
-                }
-            }
-        }
+        write_ops(&txn, ops)?;
         txn.commit()
             .with_context(|| "commit batch write transaction")?;
         Ok(())
     }
 
-    pub fn batch_write_from_channel(&self, rx: Receiver<DbOp>) {
+    /// Writes the ops received on `rx` in batches until every sender is gone.
+    /// Stops at the first failed batch; the senders then find the channel
+    /// closed.
+    pub fn batch_write_from_channel(&self, rx: Receiver<DbOp>) -> Result<()> {
         let mut buffer = Vec::with_capacity(BATCH_SIZE);
 
         loop {
@@ -305,13 +534,281 @@ impl State {
             }
 
             if !buffer.is_empty() {
-                let _ = self.batch_write(std::mem::take(&mut buffer));
+                self.batch_write(std::mem::take(&mut buffer))?;
             }
         }
+        Ok(())
     }
 }
 
-pub fn default_db_path() -> Result<PathBuf> {
+/// Applies `ops` in order within `txn`.
+fn write_ops(txn: &WriteTransaction, ops: Vec<DbOp>) -> Result<()> {
+    for op in ops {
+        match op {
+            DbOp::UpsertFile(path, metadata) => {
+                let key = path_key(&path).to_vec();
+                let value =
+                    migrate::encode(&metadata).with_context(|| "serialize file metadata")?;
+                let mut table = txn.open_table(FILE_INDEX)?;
+                let previous = table
+                    .insert(key.as_slice(), value.as_slice())?
+                    .and_then(|old| migrate::decode::<FileMetadata>(old.value()).ok());
+                let mut inode_paths = txn.open_table(INODE_PATHS)?;
+                if let Some(inode) = previous.and_then(|previous| previous.inode) {
+                    inode_paths.remove(inode_path_key(inode, &path).as_slice())?;
+                }
+                if let Some(inode) = metadata.inode {
+                    inode_paths.insert(inode_path_key(inode, &path).as_slice(), [].as_slice())?;
+                }
+            }
+            DbOp::SetCasRefcount(hash, count) => {
+                let key = hash.to_vec();
+                let value = count.to_le_bytes().to_vec();
+                let mut table = txn.open_table(CAS_INDEX)?;
+                table.insert(key.as_slice(), value.as_slice())?;
+            }
+            DbOp::MarkInodeVaulted(inode) => {
+                let key = inode_key(inode);
+                let value = 1u8;
+                let mut table = txn.open_table(VAULTED_INODES)?;
+                table.insert(key.as_slice(), std::slice::from_ref(&value))?;
+            }
+            DbOp::RemoveFileFromIndex(path) => {
+                let key = path_key(&path).to_vec();
+                let mut table = txn.open_table(FILE_INDEX)?;
+                let previous = table
+                    .remove(key.as_slice())?
+                    .and_then(|old| migrate::decode::<FileMetadata>(old.value()).ok());
+                if let Some(inode) = previous.and_then(|previous| previous.inode) {
+                    let mut inode_paths = txn.open_table(INODE_PATHS)?;
+                    inode_paths.remove(inode_path_key(inode, &path).as_slice())?;
+                }
+            }
+            DbOp::FollowMove(to, stamp) => {
+                if txn.open_table(FILE_INDEX)?.get(path_key(&to))?.is_some() {
+                    continue;
+                }
+                let vanished = vanished_paths(&txn.open_table(INODE_PATHS)?, &to, stamp.inode())?;
+                let mut table = txn.open_table(FILE_INDEX)?;
+                let mut record = None;
+                for from in vanished {
+                    let value = table.get(path_key(&from))?.map(|v| v.value().to_vec());
+                    if let Some(value) = value
+                        && migrate::decode(&value).is_ok_and(|m| stamp.matches(&m))
+                    {
+                        record = Some((from, value));
+                        break;
+                    }
+                }
+                // Otherwise the inode now belongs to another file,
+                // which is new to the index.
+                let Some((from, value)) = record else {
+                    continue;
+                };
+                table.remove(path_key(&from))?;
+                table.insert(path_key(&to), value.as_slice())?;
+                if let Ok(FileMetadata {
+                    inode: Some(inode), ..
+                }) = migrate::decode(&value)
+                {
+                    let mut inode_paths = txn.open_table(INODE_PATHS)?;
+                    inode_paths.remove(inode_path_key(inode, &from).as_slice())?;
+                    inode_paths.insert(inode_path_key(inode, &to).as_slice(), [].as_slice())?;
+                }
+                let mut originals = txn.open_table(ORIGINAL_METADATA)?;
+                let original = originals
+                    .remove(path_key(&from))?
+                    .map(|value| value.value().to_vec());
+                if let Some(original) = original {
+                    originals.insert(path_key(&to), original.as_slice())?;
+                }
+                move_run_files(txn, &from, &to)?;
+            }
+            DbOp::UnmarkInodeVaulted(inode) => {
+                let key = inode_key(inode);
+                let mut table = txn.open_table(VAULTED_INODES)?;
+                table.remove(key.as_slice())?;
+            }
+            DbOp::RemoveCasRefcount(hash) => {
+                let key = hash.to_vec();
+                let mut table = txn.open_table(CAS_INDEX)?;
+                table.remove(key.as_slice())?;
+            }
+            DbOp::SaveOriginalMetadata(path, metadata) => {
+                let key = path_key(&path).to_vec();
+                let value =
+                    migrate::encode(&metadata).with_context(|| "serialize original metadata")?;
+                let mut table = txn.open_table(ORIGINAL_METADATA)?;
+                table.insert(key.as_slice(), value.as_slice())?;
+            }
+            DbOp::RemoveOriginalMetadata(path) => {
+                let key = path_key(&path).to_vec();
+                let mut table = txn.open_table(ORIGINAL_METADATA)?;
+                table.remove(key.as_slice())?;
+            }
+            DbOp::RecordRunFile(id, path, file) => {
+                let mut key = id.to_be_bytes().to_vec();
+                key.extend_from_slice(path_key(&path));
+                let value = migrate::encode(&file).with_context(|| "serialize run file")?;
+                let mut table = txn.open_table(RUN_FILES)?;
+                table.insert(key.as_slice(), value.as_slice())?;
+                let mut run_paths = txn.open_table(RUN_PATHS)?;
+                run_paths.insert(run_path_key(&path, id).as_slice(), [].as_slice())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The form paths are stored in: absolute, with every directory resolved
+/// through symlinks. The last component is kept as it is, since it may be a
+/// symlink left by dedupe.
+pub fn canonical_path(path: &Path) -> Result<PathBuf> {
+    let absolute = std::path::absolute(path).with_context(|| format!("resolve {path:?}"))?;
+    let resolved = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(parent)
+            .with_context(|| format!("resolve {parent:?}"))?
+            .join(name),
+        _ => std::fs::canonicalize(&absolute).with_context(|| format!("resolve {path:?}"))?,
+    };
+    Ok(resolved)
+}
+
+/// The key `path` is stored under: its exact bytes, which need not be UTF-8.
+pub(crate) fn path_key(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+pub(crate) fn path_from_key(key: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(key))
+}
+
+pub(crate) fn inode_key(inode: InodeId) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&inode.dev.to_le_bytes());
+    key[8..].copy_from_slice(&inode.ino.to_le_bytes());
+    key
+}
+
+/// Paths `inode` is indexed under in `table` (an `INODE_PATHS` table) that
+/// no longer lead to it. Empty if `path` is indexed itself.
+fn vanished_paths(
+    table: &impl ReadableTable<&'static [u8], &'static [u8]>,
+    path: &Path,
+    inode: InodeId,
+) -> Result<Vec<PathBuf>> {
+    let prefix = inode_key(inode);
+    let mut vanished = Vec::new();
+    for entry in table.range(prefix.as_slice()..)? {
+        let (key, _) = entry?;
+        let Some(indexed) = key.value().strip_prefix(prefix.as_slice()) else {
+            break;
+        };
+        let indexed = path_from_key(indexed);
+        if indexed == path {
+            return Ok(Vec::new());
+        }
+        let still_there = std::fs::symlink_metadata(&indexed)
+            .is_ok_and(|meta| InodeId::from_metadata(&meta) == inode);
+        if !still_there {
+            vanished.push(indexed);
+        }
+    }
+    Ok(vanished)
+}
+
+pub(crate) fn inode_path_key(inode: InodeId, path: &Path) -> Vec<u8> {
+    let mut key = inode_key(inode).to_vec();
+    key.extend_from_slice(path_key(path));
+    key
+}
+
+pub(crate) fn run_path_key(path: &Path, id: u64) -> Vec<u8> {
+    let mut key = path_key(path).to_vec();
+    key.push(0);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Re-keys the run records of `from` to `to`, found through `RUN_PATHS`.
+fn move_run_files(txn: &WriteTransaction, from: &Path, to: &Path) -> Result<()> {
+    let mut run_paths = txn.open_table(RUN_PATHS)?;
+    let mut start = path_key(from).to_vec();
+    start.push(0);
+    let mut end = path_key(from).to_vec();
+    end.push(1);
+    let mut ids = Vec::new();
+    for entry in run_paths.range(start.as_slice()..end.as_slice())? {
+        let (key, _) = entry?;
+        ids.push(run_id_from_key(&key.value()[start.len()..])?);
+    }
+    let mut run_files = txn.open_table(RUN_FILES)?;
+    for id in ids {
+        run_paths.remove(run_path_key(from, id).as_slice())?;
+        run_paths.insert(run_path_key(to, id).as_slice(), [].as_slice())?;
+        let mut old_key = id.to_be_bytes().to_vec();
+        old_key.extend_from_slice(path_key(from));
+        let Some(value) = run_files
+            .remove(old_key.as_slice())?
+            .map(|value| value.value().to_vec())
+        else {
+            continue;
+        };
+        let mut new_key = id.to_be_bytes().to_vec();
+        new_key.extend_from_slice(path_key(to));
+        run_files.insert(new_key.as_slice(), value.as_slice())?;
+    }
+    Ok(())
+}
+
+fn run_id_from_key(key: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = key.try_into().with_context(|| "malformed run ID key")?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Renames an unreadable state database out of the way so a new one can be
+/// created, and returns where it went.
+pub fn set_aside_db() -> Result<PathBuf> {
+    let db_path = default_db_path()?;
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let aside = db_path.with_extension(format!("redb.corrupt-{secs}"));
+    std::fs::rename(&db_path, &aside)
+        .with_context(|| format!("move damaged state database to {aside:?}"))?;
+    Ok(aside)
+}
+
+/// Name of the data directory in `$HOME`.
+pub const DATA_DIR: &str = ".bdstorage";
+
+pub fn home_dir() -> Result<PathBuf> {
     let home = std::env::var("HOME").with_context(|| "HOME not set")?;
-    Ok(PathBuf::from(home).join(".imprint").join("state.redb"))
+    Ok(PathBuf::from(home))
+}
+
+/// Where state was kept before the project was renamed.
+pub fn legacy_data_dir() -> Result<PathBuf> {
+    Ok(home_dir()?.join(".imprint"))
+}
+
+/// Whether `path` is a directory itself rather than a symlink to one.
+pub fn is_real_dir(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir())
+}
+
+/// Holds the state database and the vault. Legacy state is used in place
+/// until a command that writes moves it; see [`migrate::relocate_legacy`].
+pub fn data_dir() -> Result<PathBuf> {
+    let current = home_dir()?.join(DATA_DIR);
+    let legacy = legacy_data_dir()?;
+    if !current.exists() && is_real_dir(&legacy) {
+        return Ok(legacy);
+    }
+    Ok(current)
+}
+
+pub fn default_db_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("state.redb"))
 }
@@ -0,0 +1,424 @@
+use crate::dedupe::{self, LinkType, SavedMetadata, Swap, TempCleanup};
+use crate::error::{self, BdError};
+use crate::staging::StagedFile;
+use anyhow::{Context, Result};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// A way of making `target` share the contents of `source`.
+///
+/// Strategies are tried in order by a [`LinkChain`]; adding a new one only
+/// requires implementing this trait and listing it in [`StrategyKind`].
+pub trait LinkStrategy: Send + Sync {
+    fn kind(&self) -> LinkType;
+
+    /// Cheap check for whether this strategy can work for the pair at all.
+    /// A `true` answer does not guarantee `link` succeeds.
+    fn probe(&self, source: &Path, target: &Path) -> bool;
+
+    /// Makes `target` share `source`'s data. On error `target` is untouched;
+    /// on success the returned swap must be committed to keep the result.
+    fn link(&self, source: &Path, target: &Path) -> Result<Swap>;
+
+    /// Confirms that `target` now serves the same data as `source`.
+    fn verify(&self, source: &Path, target: &Path) -> Result<bool> {
+        dedupe::compare_files(source, target)
+    }
+
+    /// Reverts a `link` of `target` that failed verification or lost its
+    /// lease, putting the original back.
+    fn undo(&self, target: &Path, swap: Swap) -> Result<()> {
+        swap.rollback()
+            .with_context(|| format!("undo {} of {target:?}", describe(self.kind())))
+    }
+}
+
+/// Strategies selectable from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StrategyKind {
+    Reflink,
+    Hardlink,
+    Symlink,
+    /// A symlink relative to the target's directory instead of absolute.
+    RelativeSymlink,
+    DedupeRange,
+    Copy,
+}
+
+impl StrategyKind {
+    fn build(self) -> Box<dyn LinkStrategy> {
+        match self {
+            StrategyKind::Reflink => Box::new(ReflinkStrategy),
+            StrategyKind::Hardlink => Box::new(HardLinkStrategy),
+            StrategyKind::Symlink => Box::new(SymlinkStrategy { relative: false }),
+            StrategyKind::RelativeSymlink => Box::new(SymlinkStrategy { relative: true }),
+            StrategyKind::DedupeRange => Box::new(DedupeRangeStrategy),
+            StrategyKind::Copy => Box::new(CopyStrategy),
+        }
+    }
+}
+
+/// An ordered list of strategies; the first one that succeeds wins.
+pub struct LinkChain {
+    strategies: Vec<Box<dyn LinkStrategy>>,
+}
+
+impl LinkChain {
+    pub fn new(kinds: &[StrategyKind]) -> Self {
+        let mut strategies: Vec<Box<dyn LinkStrategy>> = Vec::new();
+        for kind in kinds {
+            let strategy = kind.build();
+            if !strategies.iter().any(|s| s.kind() == strategy.kind()) {
+                strategies.push(strategy);
+            }
+        }
+        Self { strategies }
+    }
+
+    /// Human-readable list of the chain, e.g. `reflink/hardlink`.
+    pub fn describe(&self) -> String {
+        self.strategies
+            .iter()
+            .map(|s| describe(s.kind()))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Links `target` to `source` with the first strategy that succeeds.
+    /// Returns `None` when there is nothing to do, and a [`BdError`] when
+    /// every strategy failed.
+    pub fn link(&self, source: &Path, target: &Path) -> Result<Option<(&dyn LinkStrategy, Swap)>> {
+        if source == target {
+            return Ok(None);
+        }
+
+        let mut last = None;
+        for strategy in &self.strategies {
+            if !strategy.probe(source, target) {
+                continue;
+            }
+            match strategy.link(source, target) {
+                Ok(swap) => return Ok(Some((strategy.as_ref(), swap))),
+                Err(err) => last = Some((strategy.kind(), err)),
+            }
+        }
+
+        let path = target.to_path_buf();
+        Err(match last {
+            None => BdError::NoUsableStrategy {
+                path,
+                strategies: self.describe(),
+            },
+            Some((_, source)) if error::is_permission_denied(&source) => {
+                BdError::PermissionDenied { path, source }
+            }
+            // Only a reflink the filesystem cannot do is worth the banner;
+            // anything else that went wrong is an ordinary file error.
+            Some((LinkType::Reflink, source)) if error::is_unsupported(&source) => {
+                BdError::ReflinkUnsupported {
+                    path,
+                    source: Some(source),
+                }
+            }
+            Some((_, source)) => BdError::file(target, source),
+        }
+        .into())
+    }
+}
+
+fn describe(kind: LinkType) -> &'static str {
+    match kind {
+        LinkType::Reflink => "reflink",
+        LinkType::HardLink => "hardlink",
+        LinkType::Symlink => "symlink",
+        LinkType::DedupeRange => "dedupe-range",
+        LinkType::Copy => "copy",
+        LinkType::Deleted => "delete",
+    }
+}
+
+/// Metadata the new `target` should carry. A master moved into the vault has
+/// no file at `target`; its own inode, and so its metadata, is `source`.
+fn saved_metadata(source: &Path, target: &Path) -> Result<SavedMetadata> {
+    match SavedMetadata::capture_if_exists(target)? {
+        Some(saved) => Ok(saved),
+        None => SavedMetadata::capture(source),
+    }
+}
+
+fn same_device(source: &Path, target: &Path) -> bool {
+    let parent = target.parent().unwrap_or(Path::new("."));
+    match (std::fs::metadata(source), std::fs::metadata(parent)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+pub struct ReflinkStrategy;
+
+impl LinkStrategy for ReflinkStrategy {
+    fn kind(&self) -> LinkType {
+        LinkType::Reflink
+    }
+
+    fn probe(&self, source: &Path, target: &Path) -> bool {
+        same_device(source, target)
+    }
+
+    fn link(&self, source: &Path, target: &Path) -> Result<Swap> {
+        let mut staged = StagedFile::create(target)?;
+        staged
+            .clone_from(source)
+            .with_context(|| "reflink not supported")?;
+        let saved = saved_metadata(source, target)?;
+
+        let mut swap = staged.swap_into(target)?;
+        swap.lost = saved.apply(target)?;
+        Ok(swap)
+    }
+}
+
+pub struct HardLinkStrategy;
+
+impl LinkStrategy for HardLinkStrategy {
+    fn kind(&self) -> LinkType {
+        LinkType::HardLink
+    }
+
+    fn probe(&self, source: &Path, target: &Path) -> bool {
+        same_device(source, target)
+    }
+
+    fn link(&self, source: &Path, target: &Path) -> Result<Swap> {
+        let temp = dedupe::temp_path(target);
+        std::fs::hard_link(source, &temp).with_context(|| "create hard link")?;
+        let mut cleanup = TempCleanup::new(temp.clone());
+
+        let swap = Swap::exchange(&temp, target)?;
+        cleanup.disarm();
+
+        Ok(swap)
+    }
+
+    fn verify(&self, source: &Path, target: &Path) -> Result<bool> {
+        let a = std::fs::metadata(source).with_context(|| "read source metadata")?;
+        let b = std::fs::metadata(target).with_context(|| "read target metadata")?;
+        Ok(a.dev() == b.dev() && a.ino() == b.ino())
+    }
+}
+
+pub struct SymlinkStrategy {
+    pub relative: bool,
+}
+
+impl LinkStrategy for SymlinkStrategy {
+    fn kind(&self) -> LinkType {
+        LinkType::Symlink
+    }
+
+    fn probe(&self, _source: &Path, _target: &Path) -> bool {
+        true
+    }
+
+    fn link(&self, source: &Path, target: &Path) -> Result<Swap> {
+        let source_abs = std::fs::canonicalize(source).with_context(|| "resolve source path")?;
+        let link_target = if self.relative {
+            let target_dir = target
+                .parent()
+                .map(std::fs::canonicalize)
+                .transpose()
+                .with_context(|| "resolve target directory")?
+                .unwrap_or_default();
+            dedupe::relative_path(&target_dir, &source_abs)
+        } else {
+            source_abs
+        };
+
+        let temp = dedupe::temp_path(target);
+        std::os::unix::fs::symlink(&link_target, &temp).with_context(|| "create symlink")?;
+        let mut cleanup = TempCleanup::new(temp.clone());
+
+        let swap = Swap::exchange(&temp, target)?;
+        cleanup.disarm();
+
+        Ok(swap)
+    }
+
+    fn verify(&self, source: &Path, target: &Path) -> Result<bool> {
+        let source_abs = std::fs::canonicalize(source).with_context(|| "resolve source path")?;
+        let target_abs = std::fs::canonicalize(target).with_context(|| "resolve symlink")?;
+        Ok(source_abs == target_abs)
+    }
+}
+
+/// Shares extents in place with the FIDEDUPERANGE ioctl. The kernel compares
+/// the ranges itself and the target keeps its inode and metadata.
+pub struct DedupeRangeStrategy;
+
+impl LinkStrategy for DedupeRangeStrategy {
+    fn kind(&self) -> LinkType {
+        LinkType::DedupeRange
+    }
+
+    fn probe(&self, source: &Path, target: &Path) -> bool {
+        cfg!(target_os = "linux") && same_device(source, target)
+    }
+
+    fn link(&self, source: &Path, target: &Path) -> Result<Swap> {
+        dedupe_range(source, target)?;
+        Ok(Swap::in_place(target))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn dedupe_range(source: &Path, target: &Path) -> Result<()> {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    // Some filesystems cap a single request at 16 MiB.
+    const MAX_CHUNK: u64 = 16 * 1024 * 1024;
+
+    #[repr(C)]
+    struct FileDedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: [FileDedupeRangeInfo; 1],
+    }
+
+    // The request number encodes the size of the header only; the trailing
+    // `info` array is variable length in the kernel ABI.
+    nix::ioctl_readwrite_bad!(
+        fideduperange,
+        nix::request_code_readwrite!(0x94, 54, 24),
+        FileDedupeRange
+    );
+
+    let src = File::open(source).with_context(|| "open dedupe source")?;
+    let len = src
+        .metadata()
+        .with_context(|| "read source metadata")?
+        .len();
+
+    let dedupe_into = |dst: &File| -> Result<()> {
+        let mut offset = 0u64;
+        while offset < len {
+            let chunk = MAX_CHUNK.min(len - offset);
+            let mut range = FileDedupeRange {
+                src_offset: offset,
+                src_length: chunk,
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+                info: [FileDedupeRangeInfo {
+                    dest_fd: dst.as_raw_fd() as i64,
+                    dest_offset: offset,
+                    bytes_deduped: 0,
+                    status: 0,
+                    reserved: 0,
+                }],
+            };
+
+            // SAFETY: `range` is a correctly laid out `file_dedupe_range` with
+            // `dest_count` matching the single trailing info entry, and both file
+            // descriptors stay open for the duration of the call.
+            unsafe { fideduperange(src.as_raw_fd(), &mut range) }
+                .with_context(|| "FIDEDUPERANGE not supported")?;
+
+            let info = &range.info[0];
+            match info.status {
+                FILE_DEDUPE_RANGE_SAME => {}
+                FILE_DEDUPE_RANGE_DIFFERS => anyhow::bail!("dedupe range contents differ"),
+                errno => {
+                    return Err(std::io::Error::from_raw_os_error(-errno))
+                        .with_context(|| "FIDEDUPERANGE failed");
+                }
+            }
+            if info.bytes_deduped == 0 {
+                anyhow::bail!("FIDEDUPERANGE made no progress");
+            }
+            offset += info.bytes_deduped;
+        }
+        Ok(())
+    };
+
+    // A read-only destination is enough when the caller owns the file or may
+    // write to it; the kernel refuses others whatever the open mode. Opening
+    // for writing would break the read lease held on the target during the
+    // swap.
+    let dst = File::open(target).with_context(|| "open dedupe target")?;
+    dedupe_into(&dst)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn dedupe_range(_source: &Path, _target: &Path) -> Result<()> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+        .with_context(|| "FIDEDUPERANGE is only available on Linux")
+}
+
+/// Writes a plain copy of `source` over `target`. It saves no space, but as
+/// the last link in a chain it guarantees the target holds verified data.
+pub struct CopyStrategy;
+
+impl LinkStrategy for CopyStrategy {
+    fn kind(&self) -> LinkType {
+        LinkType::Copy
+    }
+
+    fn probe(&self, _source: &Path, _target: &Path) -> bool {
+        true
+    }
+
+    fn link(&self, source: &Path, target: &Path) -> Result<Swap> {
+        let mut staged = StagedFile::create(target)?;
+        staged
+            .copy_from(source)
+            .with_context(|| "copy source to temp file")?;
+        let saved = saved_metadata(source, target)?;
+
+        let mut swap = staged.swap_into(target)?;
+        swap.lost = saved.apply(target)?;
+        Ok(swap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undone_copies_leave_the_original_and_no_staged_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        std::fs::write(&source, b"replacement").unwrap();
+        std::fs::write(&target, b"original").unwrap();
+        let ino = std::fs::metadata(&target).unwrap().ino();
+
+        let swap = CopyStrategy.link(&source, &target).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"replacement");
+        CopyStrategy.undo(&target, swap).unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"original");
+        assert_eq!(std::fs::metadata(&target).unwrap().ino(), ino);
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["source", "target"]);
+    }
+}
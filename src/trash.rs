@@ -0,0 +1,130 @@
+use crate::types::{Hash, hash_to_hex};
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_NAME: &str = "manifest";
+
+/// A directory that receives deleted duplicates instead of unlinking them.
+///
+/// Every stashed file is recorded in `manifest` as a pair of NUL-terminated
+/// fields: the name inside the trash directory and the original path. NUL
+/// cannot appear in a path, so the manifest round-trips any filename.
+pub struct Trash {
+    dir: PathBuf,
+    manifest: BufWriter<File>,
+}
+
+/// A file that `restore_all` put back (or would put back in a dry run).
+pub struct RestoredEntry {
+    pub original: PathBuf,
+    pub size: u64,
+}
+
+impl Trash {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("create trash directory {:?}", dir))?;
+        let manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(MANIFEST_NAME))
+            .with_context(|| "open trash manifest")?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            manifest: BufWriter::new(manifest),
+        })
+    }
+
+    pub fn stash(&mut self, path: &Path, hash: &Hash) -> Result<()> {
+        let hex = hash_to_hex(hash);
+        let mut counter = 0u64;
+        let (name, dest) = loop {
+            let name = format!("{hex}.{counter}");
+            let dest = self.dir.join(&name);
+            if std::fs::symlink_metadata(&dest).is_err() {
+                break (name, dest);
+            }
+            counter += 1;
+        };
+
+        let original = std::fs::canonicalize(path.parent().unwrap_or(Path::new(".")))
+            .map(|dir| dir.join(path.file_name().unwrap_or_default()))
+            .unwrap_or_else(|_| path.to_path_buf());
+
+        if std::fs::rename(path, &dest).is_err() {
+            std::fs::copy(path, &dest).with_context(|| "copy duplicate into trash")?;
+            File::open(&dest)
+                .and_then(|file| file.sync_all())
+                .with_context(|| "sync trashed copy")?;
+            std::fs::remove_file(path).with_context(|| "remove original after copy")?;
+        }
+
+        self.manifest
+            .write_all(name.as_bytes())
+            .and_then(|_| self.manifest.write_all(b"\0"))
+            .and_then(|_| self.manifest.write_all(original.as_os_str().as_bytes()))
+            .and_then(|_| self.manifest.write_all(b"\0"))
+            .and_then(|_| self.manifest.flush())
+            .with_context(|| "write trash manifest")?;
+        Ok(())
+    }
+}
+
+/// Moves every trashed file back to its original location and drops the
+/// restored entries from the manifest. Entries whose original path is occupied
+/// again are left in the trash.
+pub fn restore_all(dir: &Path, dry_run: bool) -> Result<Vec<RestoredEntry>> {
+    let manifest_path = dir.join(MANIFEST_NAME);
+    let bytes = match std::fs::read(&manifest_path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| "read trash manifest"),
+    };
+
+    let mut fields = bytes.split(|b| *b == 0).filter(|f| !f.is_empty());
+    let mut restored = Vec::new();
+    let mut remaining = Vec::new();
+
+    while let (Some(name), Some(original)) = (fields.next(), fields.next()) {
+        let trashed = dir.join(OsStr::from_bytes(name));
+        let original = PathBuf::from(OsStr::from_bytes(original));
+
+        let size = match std::fs::metadata(&trashed) {
+            Ok(meta) => meta.len(),
+            Err(_) => continue,
+        };
+        if std::fs::symlink_metadata(&original).is_ok() {
+            remaining.push((name, original));
+            continue;
+        }
+
+        if !dry_run {
+            if let Some(parent) = original.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("recreate directory {:?}", parent))?;
+            }
+            if std::fs::rename(&trashed, &original).is_err() {
+                std::fs::copy(&trashed, &original).with_context(|| "copy file out of trash")?;
+                std::fs::remove_file(&trashed).with_context(|| "remove trashed copy")?;
+            }
+        }
+        restored.push(RestoredEntry { original, size });
+    }
+
+    if !dry_run {
+        let mut manifest = Vec::new();
+        for (name, original) in remaining {
+            manifest.extend_from_slice(name);
+            manifest.push(0);
+            manifest.extend_from_slice(original.as_os_str().as_bytes());
+            manifest.push(0);
+        }
+        std::fs::write(&manifest_path, manifest).with_context(|| "rewrite trash manifest")?;
+    }
+
+    Ok(restored)
+}
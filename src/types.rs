@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
 
 pub type Hash = [u8; 32];
 
@@ -7,8 +9,39 @@ pub struct FileMetadata {
     pub size: u64,
     pub modified: u64,
     pub hash: Hash,
+    /// Lets the record follow the file when it is moved or renamed. Unknown
+    /// for symlinks and for files indexed before it was recorded.
+    pub inode: Option<InodeId>,
+}
+
+/// Identifies an inode across filesystems: inode numbers are only unique
+/// within one device.
+///
+/// `st_dev` is used rather than a filesystem UUID. It can change across
+/// reboots for removable or network filesystems, which makes a vaulted inode
+/// look unvaulted; restore then still finds the file through its hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct InodeId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl InodeId {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+        }
+    }
 }
 
 pub fn hash_to_hex(hash: &Hash) -> String {
     blake3::Hash::from_bytes(*hash).to_hex().to_string()
 }
+
+/// Parses a hash printed by [`hash_to_hex`].
+pub fn hash_from_hex(hex: &str) -> Option<Hash> {
+    blake3::Hash::from_hex(hex)
+        .ok()
+        .map(|hash| *hash.as_bytes())
+}
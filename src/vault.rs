@@ -1,37 +1,23 @@
+use crate::device;
+use crate::staging::StagedFile;
 use crate::types::{Hash, hash_to_hex};
 use anyhow::{Context, Result};
-use std::fs::File;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-struct TempCleanup {
-    path: PathBuf,
-    armed: bool,
-}
-
-impl TempCleanup {
-    fn new(path: PathBuf) -> Self {
-        Self { path, armed: true }
-    }
-
-    fn disarm(&mut self) {
-        self.armed = false;
-    }
-}
-
-impl Drop for TempCleanup {
-    fn drop(&mut self) {
-        if !self.armed {
-            return;
-        }
-        if self.path.exists() {
-            let _ = std::fs::remove_file(&self.path);
-        }
-    }
+/// How a master file ended up in the vault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VaultOutcome {
+    /// The vault already held this hash; the master was left untouched.
+    AlreadyPresent,
+    /// The master was reflink-cloned into the vault and kept its original inode.
+    Cloned,
+    /// Cloning was impossible, so the master was moved into the vault.
+    Moved,
 }
 
 pub fn vault_root() -> Result<PathBuf> {
-    let home = std::env::var("HOME").with_context(|| "HOME not set")?;
-    Ok(PathBuf::from(home).join(".imprint").join("store"))
+    Ok(crate::state::data_dir()?.join("store"))
 }
 
 pub fn shard_path(hash: &Hash) -> Result<PathBuf> {
@@ -42,43 +28,62 @@ pub fn shard_path(hash: &Hash) -> Result<PathBuf> {
     Ok(root.join(shard_a).join(shard_b).join(hex))
 }
 
-pub fn ensure_in_vault(hash: &Hash, src: &Path) -> Result<PathBuf> {
+pub fn ensure_in_vault(hash: &Hash, src: &Path) -> Result<(PathBuf, VaultOutcome)> {
     let dest = shard_path(hash)?;
     if dest.exists() {
-        return Ok(dest);
+        return Ok((dest, VaultOutcome::AlreadyPresent));
     }
     if let Some(parent) = dest.parent() {
         std::fs::create_dir_all(parent)
             .with_context(|| format!("create vault directory {:?}", parent))?;
     }
 
-    let mut temp = dest.to_path_buf();
-    temp.set_extension("imprint_tmp");
-    if temp.exists() {
-        std::fs::remove_file(&temp).with_context(|| "remove existing temp file")?;
+    // Prefer a reflink clone so the master keeps its inode: open handles and
+    // external hard links to it stay attached to the deduplicated data.
+    let mut staged = StagedFile::create(&dest)?;
+    if staged.clone_from(src).is_ok() {
+        staged
+            .file()
+            .sync_all()
+            .with_context(|| "sync vault clone")?;
+        staged
+            .persist(&dest)
+            .with_context(|| "finalize vault file")?;
+        return Ok((dest, VaultOutcome::Cloned));
     }
+    drop(staged);
 
-    let mut cleanup = TempCleanup::new(temp.clone());
-    let mut used_copy = false;
-
-    match std::fs::rename(src, &temp) {
-        Ok(_) => {}
-        Err(_) => {
-            std::fs::copy(src, &temp).with_context(|| "copy into vault temp")?;
-            used_copy = true;
-            let file = File::open(&temp).with_context(|| "open vault temp for sync")?;
-            file.sync_all().with_context(|| "sync vault temp")?;
-        }
+    if std::fs::rename(src, &dest).is_err() {
+        let mut staged = StagedFile::create(&dest)?;
+        staged
+            .copy_from(src)
+            .with_context(|| "copy into vault temp")?;
+        staged
+            .file()
+            .sync_all()
+            .with_context(|| "sync vault temp")?;
+        staged
+            .persist(&dest)
+            .with_context(|| "finalize vault file")?;
+        std::fs::remove_file(src).with_context(|| "remove original after copy")?;
     }
 
-    std::fs::rename(&temp, &dest).with_context(|| "finalize vault file")?;
-    cleanup.disarm();
+    Ok((dest, VaultOutcome::Moved))
+}
 
-    if used_copy {
-        std::fs::remove_file(src).with_context(|| "remove original after copy")?;
+/// Whether `path` already serves its data from the vault copy at
+/// `vault_path`, as another name of it or through the same extents. Such a
+/// file gains nothing from being relinked.
+pub fn shares_data(vault_path: &Path, path: &Path) -> bool {
+    let (Ok(vault), Ok(file)) = (std::fs::metadata(vault_path), std::fs::metadata(path)) else {
+        return false;
+    };
+    if (vault.dev(), vault.ino()) == (file.dev(), file.ino()) {
+        return true;
     }
-
-    Ok(dest)
+    vault.dev() == file.dev()
+        && vault.len() == file.len()
+        && device::extents(path).is_some_and(|extents| device::extents(vault_path) == Some(extents))
 }
 
 pub fn remove_from_vault(hash: &Hash) -> Result<()> {